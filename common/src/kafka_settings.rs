@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+/// How the partitions of the topic are assigned to the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Assignment {
+    /// round-robin over the partitions of the topic, fixed for the whole run
+    Manual,
+    /// balanced by the consumer group, partitions move on rebalances
+    Group,
+}
+
+/// Kafka client settings, read from the `[kafka]` table of `Settings.toml`
+/// (the topic of the event stream is the top-level `TOPIC` key)
+/// and possibly overridden from the command line.
+/// The consumer options of the table are not relevant for the producers.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KafkaSettings {
+    pub brokers:            String,
+    pub group_id:           String,
+    pub session_timeout_ms: u64,
+    pub assignment:         Assignment,
+    // with the group assignment, a worker without partitions for this long releases its capability
+    pub idle_release_ms:    u64,
+    // partitions without records for this long do not hold back the time of the source
+    pub partition_idle_ms:  u64,
+    // the stream is over when no records arrived for this long (0 to wait forever)
+    pub end_idle_ms:        u64,
+    // the stream is over once the records present at startup have been consumed
+    pub until_caught_up:    bool,
+    #[serde(skip)]
+    pub topic:              String,
+
+    // security options, e.g. security_protocol = "ssl"
+    #[serde(default)]
+    pub security_protocol:        Option<String>,
+    #[serde(default)]
+    pub ssl_ca_location:          Option<String>,
+    #[serde(default)]
+    pub ssl_certificate_location: Option<String>,
+    #[serde(default)]
+    pub ssl_key_location:         Option<String>,
+    #[serde(default)]
+    pub ssl_key_password:         Option<String>,
+    #[serde(default)]
+    pub sasl_mechanisms:          Option<String>,
+    #[serde(default)]
+    pub sasl_username:            Option<String>,
+    #[serde(default)]
+    pub sasl_password:            Option<String>,
+
+    // any other librdkafka property, applied last
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+impl KafkaSettings {
+    pub fn from_config(config: &config::Config) -> KafkaSettings {
+        let mut settings: KafkaSettings = config.get("kafka").expect("invalid [kafka] settings");
+        settings.topic = config.get::<String>("TOPIC").unwrap();
+        settings
+    }
+
+    /// set a librdkafka property, given as `<key>=<value>`
+    pub fn set_property(&mut self, s: &str) -> Result<(), String> {
+        let mut split = s.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => {
+                self.properties.insert(key.trim().to_string(), value.trim().to_string());
+                Ok(())
+            }
+            _ => Err(format!("invalid kafka property {:?}, expected <KEY>=<VALUE>", s)),
+        }
+    }
+
+    /// brokers, security options and extra properties of the kafka clients, in the order
+    /// they have to be set on a client configuration (the extra properties last)
+    pub fn client_properties(&self) -> Vec<(String, String)> {
+        let mut properties = vec![("bootstrap.servers".to_string(), self.brokers.clone())];
+
+        let security = [
+            ("security.protocol", &self.security_protocol),
+            ("ssl.ca.location", &self.ssl_ca_location),
+            ("ssl.certificate.location", &self.ssl_certificate_location),
+            ("ssl.key.location", &self.ssl_key_location),
+            ("ssl.key.password", &self.ssl_key_password),
+            ("sasl.mechanisms", &self.sasl_mechanisms),
+            ("sasl.username", &self.sasl_username),
+            ("sasl.password", &self.sasl_password),
+        ];
+        for (key, value) in security.iter() {
+            if let Some(value) = value {
+                properties.push((key.to_string(), value.clone()));
+            }
+        }
+
+        for (key, value) in self.properties.iter() {
+            properties.push((key.clone(), value.clone()));
+        }
+        properties
+    }
+}
@@ -0,0 +1,7 @@
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate config;
+
+pub mod kafka_settings;
+pub mod wire;
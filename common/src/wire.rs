@@ -0,0 +1,42 @@
+/// Current version of the wire format written by the producer
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Kind of a kafka message: either an event or a watermark
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Post,
+    Comment,
+    Like,
+    Watermark,
+    End,
+}
+
+/// Envelope of every kafka message, encoded as JSON, e.g.
+/// {"version":1,"kind":"comment","timestamp":1554163620,"payload":"200|2|2019-04-02T00:07:00Z|..."}
+/// {"version":1,"kind":"watermark","timestamp":1554163800,"payload":""}
+/// {"version":1,"kind":"end","timestamp":1554163800,"payload":""}
+///
+/// The payload of an event is the pipe-separated record of the dataset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version:   u32,
+    pub kind:      MessageKind,
+    pub timestamp: u64,
+    #[serde(default)]
+    pub payload:   String,
+}
+
+/// encode a message in the current version of the wire format
+pub fn encode(kind: MessageKind, timestamp: u64, payload: &str) -> String {
+    let envelope = Envelope {
+        version:   SCHEMA_VERSION,
+        kind:      kind,
+        timestamp: timestamp,
+        payload:   payload.to_string(),
+    };
+    serde_json::to_string(&envelope).expect("failed to encode envelope")
+}
+
+/// marker sent on every partition once the whole dataset has been produced
+pub fn encode_end(timestamp: u64) -> String { encode(MessageKind::End, timestamp, "") }
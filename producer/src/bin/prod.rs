@@ -3,6 +3,10 @@
 extern crate lazy_static;
 
 mod utils;
+
+extern crate dspa_common;
+use dspa_common::wire;
+use utils::kafka_settings;
 use utils::prod_utils::{Event, EventStream};
 
 extern crate rand;
@@ -16,6 +20,8 @@ extern crate chrono;
 
 extern crate config;
 
+extern crate clap;
+
 use std::{thread, time};
 use std::sync::mpsc;
 
@@ -25,23 +31,32 @@ lazy_static! {
         s.merge(config::File::with_name("../Settings")).unwrap();
         s
     };
-    static ref TOPIC: String = SETTINGS.get::<String>("TOPIC").unwrap();
     static ref DELAY_PROB: f64 = SETTINGS.get::<f64>("DELAY_PROB").unwrap();
     static ref MAX_DELAY_SEC: u64 = SETTINGS.get::<u64>("MAX_DELAY_SEC").unwrap();
     static ref SPEEDUP_FACTOR: u64 = SETTINGS.get::<u64>("SPEEDUP_FACTOR").unwrap();
-    static ref NUM_PARTITIONS: i32 = SETTINGS.get::<i32>("NUM_PARTITIONS").unwrap();
 }
 
 fn main() {
-    let args = std::env::args().collect::<Vec<_>>();
-    assert!(args.len() == 2, "specify dataset as command line arg");
-    let dataset = &args[1];
+    let matches = clap::App::new("prod")
+        .arg_from_usage("<DATASET> 'Directory of the stream files'")
+        .args(&kafka_settings::args())
+        .get_matches();
+    let dataset = matches.value_of("DATASET").unwrap().to_string();
     println!("dataset is {}", dataset);
 
-    let prod1: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
+    let settings = kafka_settings::from_matches(&SETTINGS, &matches);
+    let topic = settings.topic.clone();
+
+    let mut producer_config = ClientConfig::new();
+    producer_config
         .set("produce.offset.report", "true")
-        .set("message.timeout.ms", "5000")
+        .set("message.timeout.ms", "5000");
+    kafka_settings::apply(&settings, &mut producer_config);
+
+    let num_partitions = kafka_settings::num_partitions(&settings);
+    println!("topic \"{}\" has {} partitions", topic, num_partitions);
+
+    let prod1: FutureProducer = producer_config
         .create()
         .expect("Producer creation error");
 
@@ -49,16 +64,23 @@ fn main() {
 
     let (tx, rx) = mpsc::channel::<(Event, i32)>();
 
+    let delayed_topic = topic.clone();
     let handle = thread::spawn(move || {
         let delay = time::Duration::from_millis(*MAX_DELAY_SEC*1000 / *SPEEDUP_FACTOR); // TODO wrapper
         loop {
             thread::sleep(delay);
-            while let Ok((event, partition)) = rx.try_recv() {
+            loop {
+                let (event, partition) = match rx.try_recv() {
+                    Ok(delayed) => delayed,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    // the stream is over, all the delayed events have been sent
+                    Err(mpsc::TryRecvError::Disconnected) => return,
+                };
                 println!("[delayed] event at {} is -- {:?}", event.creation_date, event);
                 prod1.send(
-                    FutureRecord::to(&TOPIC)
+                    FutureRecord::to(&delayed_topic)
                         .partition(partition)
-                        .payload(&event.payload)
+                        .payload(&event.encode())
                         .key("key"),
                         -1
                 );
@@ -92,11 +114,11 @@ fn main() {
 
         // do not delay watermarks
         if event.is_watermark {
-            for p in 0..*NUM_PARTITIONS {
+            for p in 0..num_partitions {
                 prod2.send(
-                    FutureRecord::to(&TOPIC)
+                    FutureRecord::to(&topic)
                         .partition(p)
-                        .payload(&event.payload)
+                        .payload(&event.encode())
                         .key("key"),
                     -1
                 );
@@ -111,18 +133,32 @@ fn main() {
             prev_was_delayed = false;
             println!("[ontime]  event at {} is -- {:?}", event.creation_date, event);
             prod2.send(
-                FutureRecord::to(&TOPIC)
+                FutureRecord::to(&topic)
                     .partition(partition)
-                    .payload(&event.payload)
+                    .payload(&event.encode())
                     .key("key"),
                     -1
             );
         }
 
         partition += 1;
-        partition = partition % *NUM_PARTITIONS;
+        partition = partition % num_partitions;
     }
 
     drop(tx);
     handle.join().unwrap();
+
+    // tell the consumers that the stream is over, on every partition
+    let end = wire::encode_end(prev_timestamp.unwrap_or(0));
+    for p in 0..num_partitions {
+        prod2.send(
+            FutureRecord::to(&topic)
+                .partition(p)
+                .payload(&end)
+                .key("key"),
+            -1
+        );
+    }
+    prod2.flush(time::Duration::from_secs(10));
+    println!("end of stream sent to {} partitions", num_partitions);
 }
@@ -3,6 +3,10 @@
 extern crate lazy_static;
 
 mod utils;
+
+extern crate dspa_common;
+use dspa_common::wire;
+use utils::kafka_settings;
 use utils::test_prod_utils::TestEventStream;
 
 extern crate rdkafka;
@@ -13,6 +17,8 @@ extern crate chrono;
 
 extern crate config;
 
+extern crate clap;
+
 use std::{thread, time};
 
 lazy_static! {
@@ -21,21 +27,30 @@ lazy_static! {
         s.merge(config::File::with_name("../Settings")).unwrap();
         s
     };
-    static ref TOPIC: String = SETTINGS.get::<String>("TOPIC").unwrap();
     static ref SPEEDUP_FACTOR: u64 = SETTINGS.get::<u64>("SPEEDUP_FACTOR").unwrap();
-    static ref NUM_PARTITIONS: i32 = SETTINGS.get::<i32>("NUM_PARTITIONS").unwrap();
 }
 
 fn main() {
-    let args = std::env::args().collect::<Vec<_>>();
-    assert!(args.len() == 2, "specify dataset as command line arg");
-    let dataset = &args[1];
+    let matches = clap::App::new("test_prod")
+        .arg_from_usage("<DATASET> 'Directory of the stream files'")
+        .args(&kafka_settings::args())
+        .get_matches();
+    let dataset = matches.value_of("DATASET").unwrap().to_string();
     println!("dataset is {}", dataset);
 
-    let prod: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
+    let settings = kafka_settings::from_matches(&SETTINGS, &matches);
+    let topic = settings.topic.clone();
+
+    let mut producer_config = ClientConfig::new();
+    producer_config
         .set("produce.offset.report", "true")
-        .set("message.timeout.ms", "5000")
+        .set("message.timeout.ms", "5000");
+    kafka_settings::apply(&settings, &mut producer_config);
+
+    let num_partitions = kafka_settings::num_partitions(&settings);
+    println!("topic \"{}\" has {} partitions", topic, num_partitions);
+
+    let prod: FutureProducer = producer_config
         .create()
         .expect("Producer creation error");
 
@@ -63,11 +78,11 @@ fn main() {
         thread::sleep(time::Duration::from_millis(delta));
 
         if event.is_watermark {
-            for p in 0..*NUM_PARTITIONS {
+            for p in 0..num_partitions {
                 prod.send(
-                    FutureRecord::to(&TOPIC)
+                    FutureRecord::to(&topic)
                         .partition(p)
-                        .payload(&event.payload)
+                        .payload(&event.encode())
                         .key("key"),
                     -1
                 );
@@ -78,9 +93,9 @@ fn main() {
             }
         } else {
             prod.send(
-                FutureRecord::to(&TOPIC)
+                FutureRecord::to(&topic)
                     .partition(partition)
-                    .payload(&event.payload)
+                    .payload(&event.encode())
                     .key("key"),
                 -1
             );
@@ -91,6 +106,20 @@ fn main() {
         }
 
         partition += 1;
-        partition = partition % *NUM_PARTITIONS;
+        partition = partition % num_partitions;
+    }
+
+    // tell the consumers that the stream is over, on every partition
+    let end = wire::encode_end(prev_timestamp.unwrap_or(0));
+    for p in 0..num_partitions {
+        prod.send(
+            FutureRecord::to(&topic)
+                .partition(p)
+                .payload(&end)
+                .key("key"),
+            -1
+        );
     }
+    prod.flush(time::Duration::from_secs(10));
+    println!("end of stream sent to {} partitions", num_partitions);
 }
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+
+pub use dspa_common::kafka_settings::KafkaSettings;
+
+/// command line options overriding the settings
+pub fn args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::from_usage("--brokers=[BROKERS] 'Comma separated list of kafka brokers, overrides kafka.brokers of Settings.toml'"),
+        clap::Arg::from_usage("--topic=[TOPIC] 'Kafka topic to write to, overrides TOPIC of Settings.toml'"),
+        clap::Arg::from_usage("-X --kafka-property=[KEY=VALUE]... 'Additional librdkafka property (e.g. -X security.protocol=ssl), overrides kafka.properties of Settings.toml'"),
+    ]
+}
+
+/// settings of the `[kafka]` table, overridden by the command line options
+pub fn from_matches(config: &config::Config, matches: &clap::ArgMatches) -> KafkaSettings {
+    let mut settings = KafkaSettings::from_config(config);
+
+    if let Some(brokers) = matches.value_of("brokers") {
+        settings.brokers = brokers.to_string();
+    }
+    if let Some(topic) = matches.value_of("topic") {
+        settings.topic = topic.to_string();
+    }
+    for property in matches.values_of("kafka-property").into_iter().flatten() {
+        if let Err(e) = settings.set_property(property) {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+
+    settings
+}
+
+/// set brokers, security options and extra properties on a client configuration
+pub fn apply(settings: &KafkaSettings, config: &mut ClientConfig) {
+    for (key, value) in settings.client_properties() {
+        config.set(&key, &value);
+    }
+}
+
+/// number of partitions of the topic, from the metadata of the cluster
+pub fn num_partitions(settings: &KafkaSettings) -> i32 {
+    let mut consumer_config = ClientConfig::new();
+    apply(settings, &mut consumer_config);
+    let consumer: BaseConsumer = consumer_config
+        .create()
+        .expect("Consumer creation error");
+
+    let metadata = consumer
+        .fetch_metadata(Some(&settings.topic), Duration::from_secs(5))
+        .expect("failed to fetch the topic metadata");
+    let num_partitions = metadata
+        .topics()
+        .iter()
+        .filter(|t| t.name() == settings.topic)
+        .map(|t| t.partitions().len())
+        .sum::<usize>();
+    if num_partitions == 0 {
+        panic!("topic \"{}\" has no partitions, has it been created?", settings.topic);
+    }
+    num_partitions as i32
+}
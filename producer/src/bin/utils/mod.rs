@@ -1,2 +1,3 @@
+pub mod kafka_settings;
 pub mod prod_utils;
 pub mod test_prod_utils;
@@ -9,6 +9,8 @@ use chrono::{DateTime, Duration, Utc, TimeZone};
 
 use std::cmp::min;
 
+use dspa_common::wire::{self, MessageKind};
+
 lazy_static! {
     static ref SETTINGS: config::Config = {
         let mut s = config::Config::default();
@@ -23,11 +25,12 @@ pub struct Event {
     pub timestamp: u64,
     pub payload: String,
     pub creation_date: DateTime<Utc>,
-    pub is_watermark: bool
+    pub is_watermark: bool,
+    pub kind: MessageKind
 }
 
 impl Event {
-    pub fn from_record(record: String) -> Event {
+    pub fn from_record(record: String, kind: MessageKind) -> Event {
         // Creation date is always the 3rd field
         let date_str = record.split("|").collect::<Vec<_>>()[2].trim();
         let date = Utc.datetime_from_str(date_str, "%FT%TZ")
@@ -37,18 +40,28 @@ impl Event {
             payload: record.trim().to_string(),
             creation_date: date,
             timestamp: date.timestamp() as u64,
-            is_watermark: false
+            is_watermark: false,
+            kind: kind
+        }
+    }
+
+    /// payload sent to kafka, wrapped in the wire format envelope
+    pub fn encode(&self) -> String {
+        if self.is_watermark {
+            wire::encode(self.kind, self.timestamp, "")
+        } else {
+            wire::encode(self.kind, self.timestamp, &self.payload)
         }
     }
 }
 
-pub fn read_event(reader: &mut BufReader<File>) -> Option<Event> {
+pub fn read_event(reader: &mut BufReader<File>, kind: MessageKind) -> Option<Event> {
     let mut record = String::new();
     match reader.read_line(&mut record) {
         Err(err) => { println!("ERR {}", err); None },
         Ok(0)    => { None },
-        Ok(1)    => { read_event(reader) },
-        Ok(_)    => { Some(Event::from_record(record)) }
+        Ok(1)    => { read_event(reader, kind) },
+        Ok(_)    => { Some(Event::from_record(record, kind)) }
     }
 }
 
@@ -96,7 +109,8 @@ pub fn next_watermark(cur: &Event, interval: u64) -> Option<Event> {
         timestamp: cur.timestamp + interval*60,
         payload: format!("WATERMARK|{}", date.timestamp()),
         creation_date: date,
-        is_watermark: true
+        is_watermark: true,
+        kind: MessageKind::Watermark
     })
 }
 
@@ -105,10 +119,12 @@ impl Iterator for EventStream {
 
     fn next(&mut self) -> Option<Event> {
         // If an event "slot" is None, try to fill it by reading a new record
-        if self.post_event == None { self.post_event = read_event(&mut self.posts_stream_reader); }
-        if self.like_event == None { self.like_event = read_event(&mut self.likes_stream_reader); }
+        if self.post_event == None {
+            self.post_event = read_event(&mut self.posts_stream_reader, MessageKind::Post); }
+        if self.like_event == None {
+            self.like_event = read_event(&mut self.likes_stream_reader, MessageKind::Like); }
         if self.comment_event == None {
-            self.comment_event = read_event(&mut self.comments_stream_reader); }
+            self.comment_event = read_event(&mut self.comments_stream_reader, MessageKind::Comment); }
 
         let mut res: Option<Event> = None;
 
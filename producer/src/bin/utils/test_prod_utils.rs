@@ -2,6 +2,7 @@
 extern crate lazy_static;
 
 use super::prod_utils::{Event, next_watermark};
+use dspa_common::wire::MessageKind;
 
 use std::io::{BufRead, BufReader};
 use std::fs::File;
@@ -35,7 +36,8 @@ pub struct TestEventStream {
     pub watermark_event: Option<(DateTime<Utc>, Event)>
 }
 
-pub fn read_test_event(reader: &mut BufReader<File>) -> Option<(DateTime<Utc>, Event)> {
+pub fn read_test_event(reader: &mut BufReader<File>, kind: MessageKind)
+    -> Option<(DateTime<Utc>, Event)> {
     let mut record = String::new();
     match reader.read_line(&mut record) {
         Err(err) => { println!("ERR {}", err); None },
@@ -45,7 +47,7 @@ pub fn read_test_event(reader: &mut BufReader<File>) -> Option<(DateTime<Utc>, E
             let date = Utc.datetime_from_str(date_str, "%FT%TZ")
                 .or(Utc.datetime_from_str(date_str, "%FT%T%.3fZ")).expect("failed to parse");
             let event_record = &record[21..]; // TODO: make nicer.
-            Some((date, Event::from_record(event_record.to_string())))
+            Some((date, Event::from_record(event_record.to_string(), kind)))
         }
     }
 }
@@ -81,11 +83,11 @@ impl Iterator for TestEventStream {
     fn next(&mut self) -> Option<(DateTime<Utc>, Event)> {
         // If an event "slot" is None, try to fill it by reading a new record
         if self.post_event == None {
-            self.post_event = read_test_event(&mut self.posts_stream_reader); }
+            self.post_event = read_test_event(&mut self.posts_stream_reader, MessageKind::Post); }
         if self.like_event == None {
-            self.like_event = read_test_event(&mut self.likes_stream_reader); }
+            self.like_event = read_test_event(&mut self.likes_stream_reader, MessageKind::Like); }
         if self.comment_event == None {
-            self.comment_event = read_test_event(&mut self.comments_stream_reader); }
+            self.comment_event = read_test_event(&mut self.comments_stream_reader, MessageKind::Comment); }
 
         let mut res: Option<(DateTime<Utc>, Event)> = None;
 
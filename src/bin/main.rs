@@ -8,29 +8,46 @@ extern crate serde_derive;
 
 extern crate clap;
 
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
 
 use colored::*;
 
+use serde::Serialize;
+
 extern crate timely;
-use timely::dataflow::operators::{Branch, Broadcast, Concat, Exchange, Inspect, Map};
-use timely::dataflow::{Scope, Stream};
+use timely::dataflow::operators::{Branch, Broadcast, Concat, Exchange, Inspect, Map, Probe};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
+use timely::Data;
 
 extern crate dspa;
-use dspa::event;
-use dspa::event::Event;
+use dspa::checkpoint::{self, Checkpointer};
+use dspa::event::{DeadLetter, Event, LateEvent};
+use dspa::wire::Record;
 
+use dspa::file;
 use dspa::kafka;
+use dspa::kafka::settings::{Assignment, KafkaSettings};
 
 use dspa::operators::active_posts::ActivePosts;
-use dspa::operators::active_posts::{dump_stats, Stats};
+use dspa::operators::active_posts::{dump_stats, PostStatus};
 use dspa::operators::friend_recommendations::dump_recommendations;
 use dspa::operators::friend_recommendations::FriendRecommendations;
 use dspa::operators::friend_recommendations::Score;
+use dspa::operators::late_events::LateEvents;
 use dspa::operators::post_freq::PostFrequency;
-use dspa::operators::post_trees::PostTrees;
+use dspa::operators::post_trees::{route_replies, DuplicateEvent, PostTrees};
+use dspa::operators::trending::{Trending, TrendingPost};
 use dspa::operators::unique_words::UniqueWords;
+use dspa::operators::window_notify::Amendment;
+
+use dspa::sink::json::JsonSink;
+use dspa::sink::{Keyed, Sink};
 
 lazy_static! {
     static ref SETTINGS: config::Config = {
@@ -47,6 +64,13 @@ lazy_static! {
         .collect();
 }
 
+// ids of the diagnostic streams in the JSON records, apart from the ids of the queries
+const DEAD_LETTERS_ID: usize = 100;
+const LATE_EVENTS_ID: usize = 101;
+const DUPLICATES_ID: usize = 102;
+// amendments of the windows already emitted by query Q have the id AMENDMENTS_ID + Q
+const AMENDMENTS_ID: usize = 10;
+
 /// round-robin assign the person ids to workers
 fn get_my_rec_pids(widx: usize, num_workers: usize) -> Vec<u64> {
     RECOMMENDATION_PIDS
@@ -56,7 +80,7 @@ fn get_my_rec_pids(widx: usize, num_workers: usize) -> Vec<u64> {
         .collect::<Vec<u64>>()
 }
 
-fn inspect_stats(widx: usize, stats: &HashMap<u64, Stats>) {
+fn inspect_stats(widx: usize, stats: &HashMap<u64, PostStatus>) {
     println!("{} {}", format!("[W{}]", widx).bold().red(), "stats inspect".bold().red());
     dump_stats(stats, 4);
 }
@@ -68,6 +92,68 @@ fn inspect_rec(widx: usize, rec: &HashMap<u64, Vec<Score>>) {
     }
 }
 
+fn inspect_stats_amendment(widx: usize, amendment: &Amendment<HashMap<u64, PostStatus>>) {
+    println!(
+        "{} {} {}",
+        format!("[W{}]", widx).bold().red(),
+        "stats amendment".bold().red(),
+        amendment.window
+    );
+    dump_stats(&amendment.retraction, 4);
+    dump_stats(&amendment.update, 4);
+}
+
+fn inspect_rec_amendment(widx: usize, amendment: &Amendment<HashMap<u64, Vec<Score>>>) {
+    println!(
+        "{} {} {}",
+        format!("[W{}]", widx).bold().blue(),
+        "rec amendment".bold().blue(),
+        amendment.window
+    );
+    for (pid, rec_single) in amendment.update.iter() {
+        dump_recommendations(*pid, rec_single);
+    }
+}
+
+fn inspect_trending(widx: usize, posts: &Vec<TrendingPost>) {
+    println!("{} {}", format!("[W{}]", widx).bold().red(), "trending inspect".bold().red());
+    for post in posts.iter() {
+        println!("    #{} post_id = {} -- score {:.2}", post.rank, post.post_id, post.score);
+    }
+}
+
+fn inspect_dead_letter(widx: usize, dead_letter: &DeadLetter) {
+    println!(
+        "{} {} {:?} -- {}",
+        format!("[W{}]", widx).bold().magenta(),
+        "dead letter".bold().magenta(),
+        dead_letter.record,
+        dead_letter.error
+    );
+}
+
+fn inspect_late_event(widx: usize, late_event: &LateEvent) {
+    println!(
+        "{} {} {} -- {}s late",
+        format!("[W{}]", widx).bold().magenta(),
+        "late event".bold().magenta(),
+        late_event.event.to_string(),
+        late_event.lateness
+    );
+}
+
+fn inspect_duplicate(widx: usize, duplicate: &DuplicateEvent) {
+    println!(
+        "{} {} {} -- first by person {} in post {} ({:?})",
+        format!("[W{}]", widx).bold().magenta(),
+        "duplicate".bold().magenta(),
+        duplicate.event.to_string(),
+        duplicate.first_person_id,
+        duplicate.first_root_post_id,
+        duplicate.policy
+    );
+}
+
 fn inspect_spam(widx: usize, spam_pid: &u64) {
     println!(
         "{} {} {}",
@@ -77,37 +163,150 @@ fn inspect_spam(widx: usize, spam_pid: &u64) {
     );
 }
 
-/// read event stream from kafka and deserialize string records into events
-fn get_event_stream<G>(scope: &mut G, widx: usize, num_workers: usize) -> Stream<G, Event>
-where
+/// parse a `<QUERY-ID>=<SINK>` pair
+fn parse_output(s: &str) -> Result<(usize, Sink), String> {
+    let mut split = s.splitn(2, '=');
+    let query = split.next().unwrap().parse::<usize>();
+    match (query, split.next()) {
+        (Ok(query), Some(sink)) => Ok((query, Sink::from_str(sink)?)),
+        _ => Err(format!("invalid output {:?}, expected <QUERY-ID>=<SINK>", s)),
+    }
+}
+
+/// write the output of a query to the requested sink,
+/// `probe` tracks the times whose output has been completely written
+fn write_output<G, D>(
+    stream: &Stream<G, D>,
+    query: usize,
+    widx: usize,
+    sink: Option<&Sink>,
+    kafka_settings: &KafkaSettings,
+    probe: &mut ProbeHandle<u64>,
+    inspect: fn(usize, &D),
+) where
     G: Scope<Timestamp = u64>,
+    D: Data + Serialize + Keyed,
 {
-    kafka::consumer::string_stream(scope, "events", widx, num_workers)
-        .map(|record: String| event::deserialize(record))
+    let stream = stream.probe_with(probe);
+    match sink.unwrap_or(&Sink::Inspect) {
+        Sink::Inspect => {
+            stream.inspect(move |out| inspect(widx, out));
+        }
+        Sink::Json(path) => stream.json_sink(query, widx, path),
+        Sink::Kafka(topic) => {
+            kafka::producer::json_sink(&stream, kafka_settings, topic, query, widx)
+        }
+    }
 }
 
-/// partition events by the post_id they refer to
-/// in case of replies, we don't know the root post_id at this stage
-/// => broadcast them
-fn broadcast_replies<G>(events: &Stream<G, Event>) -> Stream<G, Event>
+/// counters of a worker, reported once the stream is over
+#[derive(Clone, Debug, Default)]
+struct Summary {
+    num_events:       usize,
+    num_dead_letters: usize,
+    num_late_events:  usize,
+    num_duplicates:   usize,
+}
+
+/// where the event stream is read from
+#[derive(Clone, Debug)]
+enum Source {
+    Kafka,
+    File(PathBuf),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Source, String> {
+        if s == "kafka" {
+            Ok(Source::Kafka)
+        } else if s.starts_with("file:") {
+            Ok(Source::File(PathBuf::from(&s["file:".len()..])))
+        } else {
+            Err(format!("unknown source {:?}, expected kafka or file:<dir>", s))
+        }
+    }
+}
+
+/// read event stream from the source and deserialize records into events.
+/// Records that cannot be deserialized are routed to the dead-letter sink and counted,
+/// events that arrived too late for their windows are routed to the late-data sink.
+/// Kafka offsets are committed once the frontier of `probe` has passed the records.
+fn get_event_stream<G>(
+    scope: &mut G,
+    source: &Source,
+    dead_letter_sink: Option<&Sink>,
+    late_sink: Option<&Sink>,
+    kafka_settings: &KafkaSettings,
+    checkpointer: &Checkpointer,
+    probe: &mut ProbeHandle<u64>,
+    summary: &Rc<RefCell<Summary>>,
+    widx: usize,
+    num_workers: usize,
+) -> Stream<G, Event>
 where
     G: Scope<Timestamp = u64>,
 {
-    let (single, broad) = events.branch(|_, event| match event {
-        Event::Comment(c) => c.reply_to_comment_id != None,
-        _ => false,
-    });
+    let records = match source {
+        Source::Kafka => kafka::consumer::record_stream(
+            scope,
+            kafka_settings,
+            widx,
+            num_workers,
+            checkpointer,
+            probe,
+        ),
+        Source::File(dir) => {
+            file::source::record_stream(scope, dir.clone(), widx, num_workers, checkpointer)
+        }
+    };
 
-    let single = single.exchange(|event| event.target_id());
-    let broad = broad.broadcast();
+    let (events, dead_letters) = records
+        .map(|record: Record| {
+            record
+                .deserialize()
+                .map_err(|err| DeadLetter { record: record.payload, error: err.to_string() })
+        })
+        .branch(|_, result| result.is_err());
 
-    single.concat(&broad)
+    let dead_summary = summary.clone();
+    // only counted, the final summary reports them (stdout may be the sink of a query)
+    let dead_letters = dead_letters
+        .flat_map(|result| result.err())
+        .inspect_batch(move |_, data| dead_summary.borrow_mut().num_dead_letters += data.len());
+    let sink = dead_letter_sink;
+    let id = DEAD_LETTERS_ID;
+    write_output(&dead_letters, id, widx, sink, kafka_settings, probe, inspect_dead_letter);
+
+    let (events, late_events) = events.flat_map(|result| result.ok()).split_late(widx);
+    let late_summary = summary.clone();
+    let late_events = late_events
+        .inspect_batch(move |_, data| late_summary.borrow_mut().num_late_events += data.len());
+    let (id, sink) = (LATE_EVENTS_ID, late_sink);
+    write_output(&late_events, id, widx, sink, kafka_settings, probe, inspect_late_event);
+
+    let summary = summary.clone();
+    events.inspect_batch(move |_, data| summary.borrow_mut().num_events += data.len())
 }
 
 fn main() {
     let matches = clap::App::new("dspa-project")
-                        .arg_from_usage("-q --queries=<QUERY-ID>... 'Comma separated list of queries to run (e.g. -q 1,2), default is all queries'")
+                        .arg_from_usage("-q --queries=<QUERY-ID>... 'Comma separated list of queries to run (e.g. -q 1,2), 4 ranks the trending posts, default is all queries'")
                         .arg_from_usage("-w --workers=<NUM-WORKERS> 'Comma separated list of queries to run (e.g. -w 2), default is 1'")
+                        .arg_from_usage("-s --source=[SOURCE] 'Where to read the events from, either kafka or file:<dataset-dir>, default is kafka'")
+                        .arg_from_usage("-o --output=[OUTPUT]... 'Where to write the output of each query as <QUERY-ID>=<SINK>, with SINK one of inspect, json (stdout), json:<path> or kafka:<topic> (e.g. -o 1=json:q1.jsonl,3=kafka:spam), default is inspect'")
+                        .arg_from_usage("-d --dead-letter=[SINK] 'Where to write records that cannot be deserialized, one of inspect, json, json:<path> or kafka:<topic>, default is inspect'")
+                        .arg_from_usage("-l --late=[SINK] 'Where to write events that arrived after the time of their window, one of inspect, json, json:<path> or kafka:<topic>, default is inspect'")
+                        .arg_from_usage("--duplicates=[SINK] 'Where to write posts and comments whose id was already received, one of inspect, json, json:<path> or kafka:<topic>, default is inspect'")
+                        .arg_from_usage("--brokers=[BROKERS] 'Comma separated list of kafka brokers, overrides kafka.brokers of Settings.toml'")
+                        .arg_from_usage("--group-id=[GROUP-ID] 'Kafka consumer group, overrides kafka.group_id of Settings.toml'")
+                        .arg(clap::Arg::from_usage("--assignment=[ASSIGNMENT] 'How kafka partitions are assigned to the workers, overrides kafka.assignment of Settings.toml'").possible_values(&["manual", "group"]))
+                        .arg_from_usage("--topic=[TOPIC] 'Kafka topic of the event stream, overrides TOPIC of Settings.toml'")
+                        .arg_from_usage("-X --kafka-property=[KEY=VALUE]... 'Additional librdkafka property for consumer and producers (e.g. -X security.protocol=ssl), overrides kafka.properties of Settings.toml'")
+                        .arg_from_usage("--until-caught-up 'Stop once the kafka records present at startup have been consumed, overrides kafka.until_caught_up of Settings.toml'")
+                        .arg_from_usage("--checkpoint=[DIR] 'Periodically checkpoint the state of the operators and the consumed offsets to DIR'")
+                        .arg_from_usage("--restore=[DIR] 'Resume from the latest complete checkpoint in DIR, and keep checkpointing to it'")
                         .arg(clap::Arg::with_name("verbose").short("v").takes_value(false).required(false))
                         .get_matches();
 
@@ -116,18 +315,112 @@ fn main() {
         HashSet::from_iter(values_t!(matches, "queries", usize).unwrap_or_else(|e| e.exit()));
     let workers = value_t!(matches, "workers", usize).unwrap_or_else(|e| e.exit());
     let verbose = matches.is_present("verbose");
+    let source = matches
+        .value_of("source")
+        .map(|s| {
+            Source::from_str(s).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            })
+        })
+        .unwrap_or(Source::Kafka);
+    let sinks: HashMap<usize, Sink> = matches
+        .values_of("output")
+        .map(|outputs| {
+            outputs
+                .map(|s| {
+                    parse_output(s).unwrap_or_else(|e| {
+                        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let dead_letter_sink = matches.value_of("dead-letter").map(|s| {
+        Sink::from_str(s).unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        })
+    });
 
-    println!("[main] running queries {:?} with {} workers", queries, workers);
+    let late_sink = matches.value_of("late").map(|s| {
+        Sink::from_str(s).unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        })
+    });
+
+    let duplicates_sink = matches.value_of("duplicates").map(|s| {
+        Sink::from_str(s).unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        })
+    });
+
+    let mut kafka_settings = KafkaSettings::from_config(&SETTINGS);
+    if let Some(brokers) = matches.value_of("brokers") {
+        kafka_settings.brokers = brokers.to_string();
+    }
+    if let Some(group_id) = matches.value_of("group-id") {
+        kafka_settings.group_id = group_id.to_string();
+    }
+    match matches.value_of("assignment") {
+        Some("manual") => kafka_settings.assignment = Assignment::Manual,
+        Some("group") => kafka_settings.assignment = Assignment::Group,
+        _ => {}
+    }
+    if let Some(topic) = matches.value_of("topic") {
+        kafka_settings.topic = topic.to_string();
+    }
+    if matches.is_present("until-caught-up") {
+        kafka_settings.until_caught_up = true;
+    }
+    for property in matches.values_of("kafka-property").into_iter().flatten() {
+        kafka_settings.set_property(property).unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        });
+    }
+
+    // restoring from a directory implies checkpointing to it
+    let checkpoint_dir =
+        matches.value_of("restore").or(matches.value_of("checkpoint")).map(PathBuf::from);
+    let restore_epoch = matches.value_of("restore").map(|dir| {
+        checkpoint::latest_complete_epoch(Path::new(dir), workers).unwrap_or_else(|| {
+            let e = format!("no complete checkpoint for {} workers in {:?}", workers, dir);
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        })
+    });
+
+    println!(
+        "[main] running queries {:?} with {} workers reading from {:?}",
+        queries, workers, source
+    );
 
     let (builder, other) = timely::Configuration::Process(workers).try_build().unwrap();
-    timely::execute::execute_from(builder, other, move |worker| {
+    let start = Instant::now();
+    let guards = timely::execute::execute_from(builder, other, move |worker| {
         let widx = worker.index();
         let num_workers = worker.peers();
 
+        let checkpointer = match &checkpoint_dir {
+            Some(dir) => Checkpointer::new(dir.clone(), widx, restore_epoch),
+            None => Checkpointer::disabled(),
+        };
+        // completed frontier of the outputs of the dataflow
+        let mut probe = ProbeHandle::new();
+        let summary = Rc::new(RefCell::new(Summary::default()));
+
         worker.dataflow::<u64, _, _>(|scope| {
             // ===========================================
-            // read kakfa stream
-            let event_stream = get_event_stream(scope, widx, num_workers);
+            // read event stream (kafka or files)
+            let event_stream = get_event_stream(
+                scope,
+                &source,
+                dead_letter_sink.as_ref(),
+                late_sink.as_ref(),
+                &kafka_settings,
+                &checkpointer,
+                &mut probe,
+                &summary,
+                widx,
+                num_workers,
+            );
 
             if verbose {
                 event_stream.inspect(move |event: &Event| {
@@ -137,20 +430,66 @@ fn main() {
 
             // compute and store post_trees,
             // emit stats and recommendation updates
-            let (stat_updates, rec_updates) = broadcast_replies(&event_stream).post_trees(widx);
+            let (stat_updates, rec_updates, duplicates) =
+                route_replies(&event_stream, widx, &checkpointer).post_trees(widx, &checkpointer);
+
+            let dup_summary = summary.clone();
+            let duplicates = duplicates.inspect_batch(move |_, data| {
+                dup_summary.borrow_mut().num_duplicates += data.len()
+            });
+            write_output(
+                &duplicates,
+                DUPLICATES_ID,
+                widx,
+                duplicates_sink.as_ref(),
+                &kafka_settings,
+                &mut probe,
+                inspect_duplicate,
+            );
 
             // ===========================================
             // QUERY 1: compute active posts given the stats updates
-            if queries.contains(&1) {
-                let widx1 = widx.clone();
-                stat_updates.active_posts(widx).inspect(move |stats| inspect_stats(widx1, stats));
+            // QUERY 4: rank the trending posts of the whole cluster from the active posts
+            if queries.contains(&1) || queries.contains(&4) {
+                let (active_posts, amendments) = stat_updates.active_posts(widx, &checkpointer);
+                if queries.contains(&1) {
+                    write_output(
+                        &active_posts,
+                        1,
+                        widx,
+                        sinks.get(&1),
+                        &kafka_settings,
+                        &mut probe,
+                        inspect_stats,
+                    );
+                    // late events amend the output of the windows already emitted
+                    write_output(
+                        &amendments,
+                        AMENDMENTS_ID + 1,
+                        widx,
+                        sinks.get(&1),
+                        &kafka_settings,
+                        &mut probe,
+                        inspect_stats_amendment,
+                    );
+                }
+                if queries.contains(&4) {
+                    write_output(
+                        &active_posts.trending_posts(widx),
+                        4,
+                        widx,
+                        sinks.get(&4),
+                        &kafka_settings,
+                        &mut probe,
+                        inspect_trending,
+                    );
+                }
             }
 
             // ===========================================
             // QUERY 2: compute recommendations posts given the rec updates
             if queries.contains(&2) {
-                let widx2 = widx.clone();
-                rec_updates
+                let (recommendations, amendments) = rec_updates
                     // Updates are currently partitioned by post id. We should either:
                     // 1) re-partition by target_person (the person the event is meaningful to),
                     //    each worker should receive only events that are meaningful for one of
@@ -159,8 +498,25 @@ fn main() {
                     //    if the target_person is not among the ones it is responsible for
                     // Going with (2) for now
                     .broadcast()
-                    .friend_recommendations(&get_my_rec_pids(widx, num_workers))
-                    .inspect(move |rec| inspect_rec(widx2, rec));
+                    .friend_recommendations(&get_my_rec_pids(widx, num_workers), &checkpointer);
+                write_output(
+                    &recommendations,
+                    2,
+                    widx,
+                    sinks.get(&2),
+                    &kafka_settings,
+                    &mut probe,
+                    inspect_rec,
+                );
+                write_output(
+                    &amendments,
+                    AMENDMENTS_ID + 2,
+                    widx,
+                    sinks.get(&2),
+                    &kafka_settings,
+                    &mut probe,
+                    inspect_rec_amendment,
+                );
             }
 
             // ===========================================
@@ -170,16 +526,36 @@ fn main() {
                 let events_by_pid = event_stream.exchange(|event| event.person_id());
 
                 // compute post_frequency to detect burst of posts
-                let spam1 = events_by_pid.post_frequency(widx);
+                let spam1 = events_by_pid.post_frequency(widx, &checkpointer);
 
                 // compute unique words metric to detect unusual behavior
-                let spam2 = events_by_pid.unique_words(widx);
+                let spam2 = events_by_pid.unique_words(widx, &checkpointer);
 
                 // emit person ids marked as spammers
-                let widx3 = widx.clone();
-                spam1.concat(&spam2).inspect(move |spam| inspect_spam(widx3, spam));
+                let spam = spam1.concat(&spam2);
+                let sink = sinks.get(&3);
+                write_output(&spam, 3, widx, sink, &kafka_settings, &mut probe, inspect_spam);
             }
         });
+
+        // run until the end of the stream, when all the sources released their capabilities
+        while worker.step() {}
+
+        let summary = summary.borrow();
+        summary.clone()
     })
     .expect("Timely computation failed somehow");
-}
+
+    for (widx, summary) in guards.join().into_iter().enumerate() {
+        let summary = summary.expect("worker failed");
+        println!(
+            "[main] W{} processed {} events, {} dead letters, {} late events, {} duplicates",
+            widx,
+            summary.num_events,
+            summary.num_dead_letters,
+            summary.num_late_events,
+            summary.num_duplicates
+        );
+    }
+    println!("[main] end of stream after {:?}, shutting down", start.elapsed());
+}
\ No newline at end of file
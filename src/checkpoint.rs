@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use timely::dataflow::operators::Capability;
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    static ref CHECKPOINT_INTERVAL_SEC: u64 =
+        SETTINGS.get::<u64>("CHECKPOINT_INTERVAL_SEC").unwrap();
+}
+
+/// Periodic, watermark-aligned snapshots of the operator states.
+///
+/// Event time is split into epochs of CHECKPOINT_INTERVAL_SEC seconds.
+/// - a source writes the offsets it consumed when its capability crosses
+///   the end of an epoch: all the records emitted before belong to the epoch
+/// - every other operator writes its state when its input frontier passes
+///   the end of the epoch: in the meantime, inputs at times beyond the end
+///   of the epoch are stashed, so that the snapshot does not include them
+///
+/// The result is a consistent cut of the dataflow at the end of the epoch.
+/// Files are stored as `<dir>/<epoch>/<operator>-w<worker>.bin`; once all the
+/// registered operators of a worker wrote their state for an epoch,
+/// the worker writes the `<dir>/<epoch>/DONE-w<worker>` marker.
+/// An epoch can be restored only if all workers completed it.
+///
+/// When the time jumps over several epochs at once, only the latest one is saved, so the
+/// operators of a worker may save different epochs (e.g. a source and an operator whose
+/// frontier is held back by the sources of the other workers). Once an epoch is completed,
+/// the epochs before it are superseded: they are not waited for anymore.
+///
+/// One checkpointer is shared by all the operators of a worker.
+#[derive(Clone)]
+pub struct Checkpointer {
+    inner: Option<Rc<RefCell<CheckpointerInner>>>,
+}
+
+struct CheckpointerInner {
+    dir:           PathBuf,
+    worker_id:     usize,
+    restore_epoch: Option<u64>,
+    // name of the operators that must write their state for each epoch
+    registered:    HashSet<String>,
+    // epoch --> operators that wrote their state so far
+    written:       HashMap<u64, HashSet<String>>,
+}
+
+impl Checkpointer {
+    pub fn disabled() -> Checkpointer { Checkpointer { inner: None } }
+
+    pub fn new(dir: PathBuf, worker_id: usize, restore_epoch: Option<u64>) -> Checkpointer {
+        if let Some(epoch) = restore_epoch {
+            println!("[checkpoint] W{} restoring epoch {} from {:?}", worker_id, epoch, dir);
+        }
+        let inner = CheckpointerInner {
+            dir:           dir,
+            worker_id:     worker_id,
+            restore_epoch: restore_epoch,
+            registered:    HashSet::new(),
+            written:       HashMap::new(),
+        };
+        Checkpointer { inner: Some(Rc::new(RefCell::new(inner))) }
+    }
+
+    /// register an operator, its state will be part of every checkpoint
+    pub fn register(&self, name: &str) -> OperatorCheckpoint {
+        if let Some(inner) = &self.inner {
+            let mut inner = inner.borrow_mut();
+            assert!(inner.registered.insert(name.to_string()), "{} registered twice", name);
+        }
+        OperatorCheckpoint {
+            checkpointer: self.clone(),
+            name:         name.to_string(),
+            next_epoch:   None,
+        }
+    }
+
+    /// unregister an operator that stopped (e.g. a source that released its capability),
+    /// the following checkpoints do not wait for its state anymore
+    pub fn retire(&self, name: &str) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut inner = inner.borrow_mut();
+        inner.registered.remove(name);
+
+        // epochs that were only waiting for the retired operator are now complete
+        let registered = inner.registered.len();
+        let done = inner
+            .written
+            .iter_mut()
+            .filter_map(|(&epoch, written)| {
+                written.remove(name);
+                if written.len() == registered {
+                    Some(epoch)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        for epoch in done {
+            Checkpointer::mark_done(&mut inner, epoch);
+        }
+    }
+
+    fn path(inner: &CheckpointerInner, epoch: u64, name: &str) -> PathBuf {
+        inner.dir.join(epoch.to_string()).join(format!("{}-w{}.bin", name, inner.worker_id))
+    }
+
+    fn write<S: Serialize>(&self, name: &str, epoch: u64, state: &S) {
+        let inner = match &self.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let mut inner = inner.borrow_mut();
+
+        let path = Checkpointer::path(&inner, epoch, name);
+        fs::create_dir_all(path.parent().unwrap()).expect("failed to create checkpoint dir");
+        let writer = BufWriter::new(File::create(&path).expect("failed to create checkpoint"));
+        bincode::serialize_into(writer, state).expect("failed to write checkpoint");
+
+        // mark the epoch as done once all the operators wrote their state
+        let done = {
+            let written = inner.written.entry(epoch).or_insert(HashSet::new());
+            written.insert(name.to_string());
+            written.len()
+        } == inner.registered.len();
+
+        if done {
+            Checkpointer::mark_done(&mut inner, epoch);
+        }
+    }
+
+    /// write the marker of a completed epoch, the epochs before it are superseded
+    fn mark_done(inner: &mut CheckpointerInner, epoch: u64) {
+        inner.written.retain(|&e, _| e > epoch);
+        let marker = inner.dir.join(epoch.to_string()).join(format!("DONE-w{}", inner.worker_id));
+        File::create(marker).expect("failed to write checkpoint marker");
+    }
+
+    fn read<S: DeserializeOwned>(&self, name: &str) -> Option<(u64, S)> {
+        let inner = self.inner.as_ref()?.borrow();
+        let epoch = inner.restore_epoch?;
+
+        let path = Checkpointer::path(&inner, epoch, name);
+        match File::open(&path) {
+            Ok(file) => {
+                let state = bincode::deserialize_from(BufReader::new(file))
+                    .expect(&format!("failed to read checkpoint {:?}", path));
+                Some((epoch, state))
+            }
+            Err(_) => {
+                println!("[checkpoint] {:?} not found, starting from an empty state", path);
+                None
+            }
+        }
+    }
+}
+
+/// find the latest epoch in `dir` that has been completed by all the workers
+pub fn latest_complete_epoch(dir: &Path, num_workers: usize) -> Option<u64> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+        .filter(|epoch| {
+            (0..num_workers)
+                .all(|w| dir.join(epoch.to_string()).join(format!("DONE-w{}", w)).exists())
+        })
+        .max()
+}
+
+/// Checkpointing logic of a single operator.
+pub struct OperatorCheckpoint {
+    checkpointer: Checkpointer,
+    name:         String,
+    // end of the epoch to be checkpointed next
+    next_epoch:   Option<u64>,
+}
+
+impl OperatorCheckpoint {
+    pub fn enabled(&self) -> bool { self.checkpointer.inner.is_some() }
+
+    /// load the state of the operator from the epoch being restored (if any)
+    pub fn restore<S: DeserializeOwned>(&mut self) -> Option<S> {
+        let (epoch, state) = self.checkpointer.read(&self.name)?;
+        self.next_epoch = Some(epoch + *CHECKPOINT_INTERVAL_SEC);
+        Some(state)
+    }
+
+    /// start tracking epochs from the first time observed by the operator
+    pub fn start(&mut self, time: u64) {
+        if self.next_epoch.is_none() {
+            self.next_epoch =
+                Some((time / *CHECKPOINT_INTERVAL_SEC + 1) * *CHECKPOINT_INTERVAL_SEC);
+        }
+    }
+
+    /// whether data at `time` is beyond the epoch being checkpointed
+    pub fn is_ahead(&mut self, time: u64) -> bool {
+        self.start(time);
+        self.enabled() && time >= self.next_epoch.unwrap()
+    }
+
+    /// if the frontier has passed the end of the epoch, return the epoch to checkpoint;
+    /// if it passed the end of several epochs at once, only the latest one is checkpointed
+    pub fn ready(&self, frontier: &[u64]) -> Option<u64> {
+        let next_epoch = self.next_epoch?;
+        match frontier.iter().min() {
+            Some(&time) if self.enabled() && time >= next_epoch => {
+                Some(time / *CHECKPOINT_INTERVAL_SEC * *CHECKPOINT_INTERVAL_SEC)
+            }
+            _ => None,
+        }
+    }
+
+    /// write the state of the operator for the given epoch, and move on to the next one
+    pub fn save<S: Serialize>(&mut self, epoch: u64, state: &S) {
+        self.checkpointer.write(&self.name, epoch, state);
+        self.next_epoch = Some(epoch + *CHECKPOINT_INTERVAL_SEC);
+    }
+
+    /// the operator stopped, the following checkpoints do not wait for its state
+    pub fn retire(&self) { self.checkpointer.retire(&self.name); }
+
+    /// end of the epoch to be checkpointed next
+    pub fn next_epoch(&self) -> u64 { self.next_epoch.unwrap_or(std::u64::MAX) }
+
+    /// for sources: the capability has been downgraded from `from` to `to`,
+    /// return the epoch to checkpoint if the end of one has been crossed
+    pub fn downgraded(&mut self, from: u64, to: u64) -> Option<u64> {
+        self.start(from);
+        self.ready(&[to])
+    }
+
+    /// to be called by operators on every activation, after processing the input.
+    ///
+    /// If the frontier passed the end of the epoch, process the stashed batches
+    /// of the epoch, save the state and release the batches of the following epoch.
+    /// Once the input is exhausted all the stashed batches are released.
+    pub fn align<S, D, F>(
+        &mut self,
+        frontier: &[u64],
+        stash: &mut Stash<D>,
+        state: &mut S,
+        mut process: F,
+    ) where
+        S: Serialize,
+        F: FnMut(&mut S, Capability<u64>, Vec<D>),
+    {
+        if let Some(&time) = frontier.iter().min() {
+            self.start(time);
+        }
+
+        if frontier.is_empty() {
+            for (cap, data) in stash.release(std::u64::MAX) {
+                process(state, cap, data);
+            }
+        } else if let Some(epoch) = self.ready(frontier) {
+            for (cap, data) in stash.release(epoch) {
+                process(state, cap, data);
+            }
+            self.save(epoch, state);
+            for (cap, data) in stash.release(self.next_epoch()) {
+                process(state, cap, data);
+            }
+        }
+    }
+}
+
+/// Input batches (and their capabilities) held back while waiting
+/// for the frontier to pass the end of the epoch being checkpointed.
+pub struct Stash<D> {
+    batches: Vec<(Capability<u64>, Vec<D>)>,
+}
+
+impl<D> Stash<D> {
+    pub fn new() -> Stash<D> { Stash { batches: Vec::new() } }
+
+    pub fn push(&mut self, cap: Capability<u64>, data: Vec<D>) { self.batches.push((cap, data)); }
+
+    /// remove and return the batches at times before `time`, in time order
+    pub fn release(&mut self, time: u64) -> Vec<(Capability<u64>, Vec<D>)> {
+        let (mut released, kept) =
+            self.batches.drain(..).partition::<Vec<_>, _>(|(cap, _)| *cap.time() < time);
+        self.batches = kept;
+        released.sort_by_key(|(cap, _)| *cap.time());
+        released
+    }
+}
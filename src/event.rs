@@ -1,8 +1,11 @@
 use abomonation;
+use std::error::Error;
 use std::fmt;
 use std::string::ToString;
 
-#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+use chrono::{DateTime, TimeZone, Utc};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ID {
     Post(u64),
     Comment(u64),
@@ -34,7 +37,7 @@ impl fmt::Display for ID {
 /// A generic Event type.
 /// Events are parsed using serde from string records read from kafka.
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     Post(PostEvent),
     Like(LikeEvent),
@@ -69,10 +72,19 @@ impl Event {
         }
     }
 
+    /// fill in the ids skipped by serde, e.g. after reading the event back from a checkpoint
+    pub fn init(self) -> Event {
+        match self {
+            Event::Post(post) => Event::Post(post.init()),
+            Event::Like(like) => Event::Like(like.init()),
+            Event::Comment(comm) => Event::Comment(comm.init()),
+        }
+    }
+
     pub fn target_id(&self) -> u64 {
         match self {
             Event::Post(post) => post.post_id_u64,
-            Event::Like(like) => like.post_id_u64,
+            Event::Like(like) => like.post_id_u64.or(like.comment_id_u64).unwrap(),
             Event::Comment(comm) => {
                 comm.reply_to_post_id_u64.or(comm.reply_to_comment_id_u64).unwrap()
             }
@@ -90,37 +102,55 @@ impl fmt::Display for Event {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// A like to a post, or to a comment: the id of the comment is then
+/// an optional 4th field of the record, after an empty post id
+/// (records with both ids are rejected).
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LikeEvent {
     pub person_id: u64,
 
     #[serde(skip)]
-    pub post_id: ID,
-    pub post_id_u64: u64,
+    pub post_id: Option<ID>,
+    pub post_id_u64: Option<u64>,
 
     pub creation_date: chrono::DateTime<chrono::Utc>,
+
+    #[serde(skip)]
+    pub comment_id: Option<ID>,
+    // missing from the records of likes to posts
+    #[serde(default)]
+    pub comment_id_u64: Option<u64>,
 }
 
 impl LikeEvent {
     fn init(mut self) -> Self {
-        self.post_id = ID::Post(self.post_id_u64);
+        if let Some(id) = self.post_id_u64 {
+            self.post_id = Some(ID::Post(id));
+        }
+        if let Some(id) = self.comment_id_u64 {
+            self.comment_id = Some(ID::Comment(id));
+        }
         self
     }
+
+    /// id of the liked post or comment
+    pub fn liked_id(&self) -> ID { self.post_id.or(self.comment_id).unwrap() }
 }
 
 impl ToString for LikeEvent {
     fn to_string(&self) -> String {
         format!(
-            "like at timestamp {} -- to post_id = {}, by = {}, date = {}",
+            "like at timestamp {} -- to_{} = {}, by = {}, date = {}",
             self.creation_date.timestamp(),
-            self.post_id,
+            if self.post_id != None { "post" } else { "comment" },
+            self.liked_id(),
             self.person_id,
             self.creation_date
         )
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CommentEvent {
     #[serde(skip)]
     pub comment_id: ID,
@@ -170,7 +200,7 @@ impl ToString for CommentEvent {
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PostEvent {
     #[serde(skip)]
     pub post_id: ID,
@@ -207,21 +237,136 @@ impl ToString for PostEvent {
     }
 }
 
-pub fn deserialize(record: String) -> Event {
-    let reader = || {
-        csv::ReaderBuilder::new().has_headers(false).delimiter(b'|').from_reader(record.as_bytes())
+/// parse the creation date of a record
+pub fn parse_date(date_str: &str) -> Option<DateTime<Utc>> {
+    Utc.datetime_from_str(date_str, "%FT%TZ").or(Utc.datetime_from_str(date_str, "%FT%T%.3fZ")).ok()
+}
+
+/// The kind of event a record encodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Post,
+    Comment,
+    Like,
+}
+
+impl EventKind {
+    /// the kind of a record is determined by its number of fields
+    fn from_num_fields(num_fields: usize) -> Result<EventKind, DeserializeError> {
+        match num_fields {
+            11 => Ok(EventKind::Post),
+            9 => Ok(EventKind::Comment),
+            3 | 4 => Ok(EventKind::Like),
+            _ => Err(DeserializeError::UnknownKind { num_fields: num_fields }),
+        }
+    }
+}
+
+/// Error returned when a record cannot be deserialized into an `Event`.
+#[derive(Debug, Clone)]
+pub enum DeserializeError {
+    // the record could not be split into fields
+    Unreadable { reason: String },
+    // the number of fields does not match any kind of event
+    UnknownKind { num_fields: usize },
+    // the fields could not be parsed as an event of the given kind
+    Malformed { kind: EventKind, reason: String },
+    // the record was encoded with a newer version of the wire format
+    UnsupportedVersion { version: u32 },
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::Unreadable { reason } => write!(f, "unreadable record: {}", reason),
+            DeserializeError::UnknownKind { num_fields } => {
+                write!(f, "unknown record kind with {} fields", num_fields)
+            }
+            DeserializeError::Malformed { kind, reason } => {
+                write!(f, "malformed {:?} record: {}", kind, reason)
+            }
+            DeserializeError::UnsupportedVersion { version } => {
+                write!(f, "unsupported wire format version {}", version)
+            }
+        }
+    }
+}
+
+impl Error for DeserializeError {}
+
+/// A record that could not be deserialized, routed to the dead-letter stream
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub record: String,
+    pub error:  String,
+}
+
+/// An event that arrived after the time of its window, routed to the late-data stream
+#[derive(Debug, Clone, Serialize)]
+pub struct LateEvent {
+    pub event:    Event,
+    // how late the event was, in seconds
+    pub lateness: u64,
+}
+
+impl abomonation::Abomonation for LateEvent {}
+
+/// deserialize a record, its kind is inferred from the number of fields
+pub fn deserialize(record: String) -> Result<Event, DeserializeError> {
+    deserialize_record(None, record)
+}
+
+/// deserialize a record whose kind is known (e.g. from the wire format envelope)
+pub fn deserialize_as(kind: EventKind, record: String) -> Result<Event, DeserializeError> {
+    deserialize_record(Some(kind), record)
+}
+
+fn deserialize_record(kind: Option<EventKind>, record: String) -> Result<Event, DeserializeError> {
+    let mut reader =
+        csv::ReaderBuilder::new().has_headers(false).delimiter(b'|').from_reader(record.as_bytes());
+
+    let malformed = |kind, reason: String| DeserializeError::Malformed { kind: kind, reason: reason };
+
+    let fields = match reader.records().next() {
+        Some(Ok(fields)) => fields,
+        Some(Err(err)) => return Err(DeserializeError::Unreadable { reason: err.to_string() }),
+        None => return Err(DeserializeError::Unreadable { reason: "empty record".to_string() }),
     };
 
-    // Note: the order below matters, if you try to deserialize a `LikeEvent`
-    //       given a post record it would succeed. Maybe there is a way to enforce
-    //       a perfect match of the fields of the struct?
-    if let Ok(post) = reader().deserialize::<PostEvent>().next().unwrap() {
-        Event::Post(post.init())
-    } else if let Ok(comment) = reader().deserialize::<CommentEvent>().next().unwrap() {
-        Event::Comment(comment.init())
-    } else if let Ok(like) = reader().deserialize::<LikeEvent>().next().unwrap() {
-        Event::Like(like.init())
-    } else {
-        panic!("could not deserialize record {:?}", record);
+    // dispatch on the kind of the record, rather than trying each event type in turn
+    let kind = match kind {
+        Some(kind) => kind,
+        None => EventKind::from_num_fields(fields.len())?,
+    };
+    match kind {
+        EventKind::Post => fields
+            .deserialize::<PostEvent>(None)
+            .map(|post| Event::Post(post.init()))
+            .map_err(|err| malformed(kind, err.to_string())),
+        EventKind::Comment => {
+            let comment = fields
+                .deserialize::<CommentEvent>(None)
+                .map_err(|err| malformed(kind, err.to_string()))?;
+            if comment.reply_to_post_id_u64.is_none() && comment.reply_to_comment_id_u64.is_none() {
+                return Err(malformed(kind, "reply to neither a post nor a comment".to_string()));
+            }
+            Ok(Event::Comment(comment.init()))
+        }
+        EventKind::Like => {
+            let like = fields
+                .deserialize::<LikeEvent>(None)
+                .map_err(|err| malformed(kind, err.to_string()))?;
+            match (like.post_id_u64, like.comment_id_u64) {
+                (None, None) => {
+                    return Err(malformed(kind, "like to neither a post nor a comment".to_string()))
+                }
+                (Some(_), Some(_)) => {
+                    return Err(malformed(kind, "like to both a post and a comment".to_string()))
+                }
+                _ => {}
+            }
+            Ok(Event::Like(like.init()))
+        }
     }
 }
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::event::{parse_date, EventKind};
+
+// name of the stream files, both the plural (tests) and singular (full dataset) form
+const STREAM_FILES: [(EventKind, &str, &str); 3] = [
+    (EventKind::Post, "posts_event_stream.csv", "post_event_stream.csv"),
+    (EventKind::Like, "likes_event_stream.csv", "like_event_stream.csv"),
+    (EventKind::Comment, "comments_event_stream.csv", "comment_event_stream.csv"),
+];
+
+/// A single record read from one of the stream files.
+///
+/// `order` is the time at which the record should be replayed:
+/// the processing time column of the test streams if present,
+/// the creation date of the event otherwise.
+/// `timestamp` is None if the creation date cannot be parsed,
+/// the record is forwarded anyway and dealt with by the deserializer.
+#[derive(Debug, Clone, Ord, PartialOrd, PartialEq, Eq)]
+pub struct Record {
+    pub order:     u64,
+    pub timestamp: Option<u64>,
+    pub payload:   String,
+}
+
+impl Record {
+    fn from_line(line: &str) -> Record {
+        let line = line.trim();
+
+        // test streams are prefixed by the processing time of the record
+        let first = line.split('|').next().unwrap();
+        let (order, payload) = match parse_date(first) {
+            Some(date) => (Some(date.timestamp() as u64), line.get(first.len() + 1..).unwrap_or("")),
+            None => (None, line),
+        };
+
+        // creation date is always the 3rd field
+        let timestamp = payload
+            .split('|')
+            .nth(2)
+            .and_then(|date_str| parse_date(date_str.trim()))
+            .map(|date| date.timestamp() as u64);
+
+        Record {
+            order:     order.or(timestamp).unwrap_or(0),
+            timestamp: timestamp,
+            payload:   payload.to_string(),
+        }
+    }
+}
+
+fn read_record(reader: &mut BufReader<File>) -> Option<Record> {
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Err(err) => {
+                println!("ERR {}", err);
+                return None;
+            }
+            Ok(0) => return None,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                // skip empty lines
+                if !line.trim().is_empty() {
+                    return Some(Record::from_line(&line));
+                }
+            }
+        }
+    }
+}
+
+/// Merge the posts, likes and comments stream files of a dataset
+/// directory into a single stream of records, sorted by replay time.
+/// Each record comes with the kind of events of the file it was read from.
+///
+/// Same merge logic as the `EventStream` of the producer: keep the next
+/// record of each file in a "slot" and always consume the earliest one.
+pub struct EventStream {
+    kinds:   Vec<EventKind>,
+    readers: Vec<BufReader<File>>,
+    // next record for each stream
+    slots:   Vec<Option<Record>>,
+}
+
+impl EventStream {
+    pub fn new(dir: &Path) -> EventStream {
+        let readers = STREAM_FILES
+            .iter()
+            .map(|(_, plural, singular)| {
+                let path = if dir.join(plural).exists() { dir.join(plural) } else { dir.join(singular) };
+                let mut reader = BufReader::new(
+                    File::open(&path).expect(&format!("file {:?} not found", path)),
+                );
+                // discard headers
+                reader.read_until(b'\n', &mut Vec::new()).unwrap();
+                reader
+            })
+            .collect::<Vec<_>>();
+
+        EventStream {
+            kinds:   STREAM_FILES.iter().map(|(kind, _, _)| *kind).collect(),
+            slots:   vec![None; readers.len()],
+            readers: readers,
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = (EventKind, Record);
+
+    fn next(&mut self) -> Option<(EventKind, Record)> {
+        // If an event "slot" is None, try to fill it by reading a new record
+        for (slot, reader) in self.slots.iter_mut().zip(self.readers.iter_mut()) {
+            if slot.is_none() {
+                *slot = read_record(reader);
+            }
+        }
+
+        // Find the event that happened at the earliest time and consume it
+        let (idx, _) = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, slot)| slot.as_ref().map(|record| (idx, record)))
+            .min_by(|(_, r1), (_, r2)| r1.cmp(r2))?;
+
+        self.slots[idx].take().map(|record| (self.kinds[idx], record))
+    }
+}
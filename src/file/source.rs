@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+
+use timely::dataflow::operators::generic::source;
+use timely::dataflow::{Scope, Stream};
+
+use super::reader::EventStream;
+use crate::checkpoint::Checkpointer;
+use crate::wire::Record;
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    static ref MAX_DELAY_SEC: u64 = SETTINGS.get::<u64>("MAX_DELAY_SEC").unwrap();
+}
+
+/// replay the stream files of the dataset directory `dir` and return
+/// a stream of records, as the kafka consumer does.
+///
+/// Records are assigned to workers in a round-robin fashion, but every
+/// worker reads all of them so that capabilities advance at the same pace.
+/// As for the kafka source, the capability is downgraded using the event
+/// timestamps and the maximum bounded delay; it is dropped once all
+/// the files have been consumed.
+///
+/// The position in the merged stream is checkpointed at the end of every epoch,
+/// records before the restored position are skipped.
+pub fn record_stream<G>(
+    scope: &G,
+    dir: PathBuf,
+    index: usize,
+    peers: usize,
+    checkpointer: &Checkpointer,
+) -> Stream<G, Record>
+where
+    G: Scope<Timestamp = u64>,
+{
+    let mut event_stream = EventStream::new(&dir);
+    let mut checkpoint = checkpointer.register("FileSource");
+
+    // position of the next record in the merged stream
+    let mut position = checkpoint.restore::<usize>().unwrap_or(0);
+    for _ in event_stream.by_ref().take(position) {}
+
+    println!("[file-source] replaying event streams from {:?} at record {}", dir, position);
+
+    source(scope, "FileRecordSourceStream", move |capability, info| {
+        let activator = scope.activator_for(&info.address[..]);
+        let mut cap = Some(capability);
+
+        move |output| {
+            let mut complete = false;
+            if let Some(capability) = cap.as_mut() {
+                // Indicate that we should run again.
+                activator.activate();
+
+                // Emit records until the capability is downgraded, so that
+                // downstream operators observe time advancing in small steps.
+                loop {
+                    let (kind, record) = match event_stream.next() {
+                        Some(next) => next,
+                        None => {
+                            complete = true;
+                            break;
+                        }
+                    };
+
+                    if position % peers == index {
+                        output.session(capability).give(Record::csv(Some(kind), record.payload));
+                    }
+                    position += 1;
+
+                    let time = *capability.time();
+                    let watermark = record.timestamp.map(|ts| ts.saturating_sub(*MAX_DELAY_SEC));
+                    match watermark {
+                        Some(watermark) if watermark > time => {
+                            // downgrade the capability by considering the event timestamp
+                            // and the maximum bounded delay
+                            capability.downgrade(&watermark);
+                            // all the records replayed so far belong to the epoch
+                            if let Some(epoch) = checkpoint.downgraded(time, *capability.time()) {
+                                checkpoint.save(epoch, &position);
+                            }
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if complete {
+                println!("[file-source] W{} replayed {} records", index, position);
+                cap = None;
+            }
+        }
+    })
+}
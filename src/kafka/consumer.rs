@@ -1,98 +1,335 @@
-use timely::dataflow::{Scope, Stream};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use timely::dataflow::operators::{Capability, ToStream};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
 
 use rdkafka::config::ClientConfig;
 use rdkafka::consumer::{BaseConsumer, Consumer, EmptyConsumerContext};
-use rdkafka::TopicPartitionList;
-
-use chrono::{TimeZone, Utc};
+use rdkafka::{Message as KafkaMessage, Offset, TopicPartitionList};
 
+use super::settings::{self, Assignment, KafkaSettings};
 use super::source::kafka_source;
+use super::watermarks::PartitionWatermarks;
+use crate::checkpoint::{Checkpointer, OperatorCheckpoint};
+use crate::wire::{self, Message, Record};
 
-lazy_static! {
-    static ref SETTINGS: config::Config = {
-        let mut s = config::Config::default();
-        s.merge(config::File::with_name("Settings")).unwrap();
-        s
-    };
-    static ref MAX_DELAY_SEC: u64 = SETTINGS.get::<u64>("MAX_DELAY_SEC").unwrap();
-    static ref NUM_PARTITIONS: i32 = SETTINGS.get::<i32>("NUM_PARTITIONS").unwrap();
-}
+// whether the source should release its capability, called on every activation
+type IdleCheck = Box<dyn FnMut(&BaseConsumer<EmptyConsumerContext>) -> bool>;
 
-/// subscribe to the requested topic and return a stream
-/// of Kafka records (strings).
-/// Kafka partition are assigned to workers in a round-robin fashion.
-pub fn string_stream<'a, G>(
+/// subscribe to the topic of the settings and return a stream
+/// of Kafka records, decoded from the wire format.
+///
+/// Partitions are discovered from the metadata of the topic and either
+/// - assigned to the workers in a round-robin fashion (`assignment = "manual"`), or
+/// - balanced by the consumer group across all the consumers subscribed
+///   to the topic (`assignment = "group"`), partitions move on rebalances.
+/// Workers without partitions release their capability, so that they do not hold back
+/// the frontier: right away with the manual assignment, after `idle_release_ms` without
+/// partitions with the group assignment (the consumer then leaves the group).
+///
+/// The stream of a partition ends when the producer sends the end-of-stream marker,
+/// when the high-water mark at startup is reached (`until_caught_up`) or
+/// when no records arrived for `end_idle_ms` (if not 0); once the stream of all its
+/// partitions is over the worker releases its capability, so that the dataflow can complete.
+///
+/// The time of the stream advances with the low watermark of the partitions of the worker
+/// (see `PartitionWatermarks`), partitions idle for `partition_idle_ms` do not hold it back.
+///
+/// Offsets are committed to the consumer group once the frontier of `probe`
+/// (the output of the dataflow) has passed the records, a restart resumes from there.
+/// The offsets consumed from each partition are also checkpointed at the end of every epoch;
+/// when restoring, the consumer starts from the restored offsets (manual assignment only).
+pub fn record_stream<'a, G>(
     scope: &mut G,
-    topic: &'static str,
+    settings: &KafkaSettings,
     index: usize,
     peers: usize,
-) -> Stream<G, String>
+    checkpointer: &Checkpointer,
+    probe: &ProbeHandle<u64>,
+) -> Stream<G, Record>
 where
     G: Scope<Timestamp = u64>,
 {
-    let brokers = "localhost:9092";
+    let topic = settings.topic.as_str();
 
     let mut consumer_config = ClientConfig::new();
     consumer_config
         .set("produce.offset.report", "true")
         .set("auto.offset.reset", "smallest")
-        .set("group.id", "dspa")
+        .set("group.id", &settings.group_id)
         .set("enable.auto.commit", "false")
         .set("enable.partition.eof", "false")
         .set("auto.offset.reset", "earliest")
-        .set("session.timeout.ms", "6000")
-        .set("bootstrap.servers", &brokers);
+        .set("session.timeout.ms", &settings.session_timeout_ms.to_string());
+    settings::apply(settings, &mut consumer_config);
 
     // Create a Kafka consumer.
     let consumer: BaseConsumer<EmptyConsumerContext> =
         consumer_config.create().expect("Couldn't create consumer");
-    consumer.subscribe(&[&topic.to_string()]).expect("Failed to subscribe to topic");
-
-    // assign kafka partition to workers in a round-robin fashion
-    let mut partition_list = TopicPartitionList::new();
-    let mut partition = index as i32;
-    while partition < *NUM_PARTITIONS {
-        partition_list.add_partition(topic, partition);
-        partition += peers as i32;
+
+    let partitions = topic_partitions(&consumer, topic);
+    println!("[kafka-consumer] topic \"{}\" has {} partitions", topic, partitions.len());
+
+    // round-robin share of the partitions of this worker
+    let my_partitions = partitions
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &partition)| if i % peers == index { Some(partition) } else { None })
+        .collect::<Vec<_>>();
+
+    if settings.assignment == Assignment::Manual && my_partitions.is_empty() {
+        println!("[kafka-consumer] W{} has no partitions, releasing its capability", index);
+        return Vec::<Record>::new().to_stream(scope);
     }
-    consumer.assign(&partition_list).expect("error in assigning partition list");
-
-    println!(
-        "[kafka-consumer] subscribed to {} partitions of topic \"{}\"",
-        partition_list.count(),
-        topic
-    );
-
-    kafka_source(scope, "KafkaStringSourceStream", consumer, |bytes, capability, output| {
-        // If the bytes are utf8, convert to string and send.
-        if let Ok(text) = std::str::from_utf8(bytes) {
-            let timestamp = if text.starts_with("WATERMARK") {
-                // format is WATERMARK|<timestamp>
-                // use watermark to downgrade capabilities
-                let t = text.split("|").collect::<Vec<_>>()[1].trim();
-                t.parse::<u64>().unwrap()
-            } else {
-                // forward only real events
-                output.session(capability).give(text.to_string());
-                let date_str = text.split("|").collect::<Vec<_>>()[2].trim();
-                let date = Utc
-                    .datetime_from_str(date_str, "%FT%TZ")
-                    .or(Utc.datetime_from_str(date_str, "%FT%T%.3fZ"))
-                    .expect("failed to parse");
-
-                date.timestamp() as u64
+
+    let mut checkpoint = checkpointer.register("KafkaSource");
+    let offsets = checkpoint.restore::<HashMap<i32, i64>>().unwrap_or_default();
+    let idle_timeout = Duration::from_millis(settings.partition_idle_ms);
+    // the group assignment is only known after the first rebalance
+    let assigned = match settings.assignment {
+        Assignment::Manual => my_partitions.clone(),
+        Assignment::Group => Vec::new(),
+    };
+    let state = Rc::new(RefCell::new(SourceState {
+        checkpoint:  checkpoint,
+        offsets:     offsets,
+        watermarks:  PartitionWatermarks::new(&assigned, idle_timeout),
+        partitions:  assigned,
+        end_offsets: HashMap::new(),
+        finished:    HashSet::new(),
+        last_record: Instant::now(),
+        ended:       false,
+    }));
+
+    if settings.until_caught_up {
+        // the stream of a partition is over once the records present at startup are consumed
+        for &partition in partitions.iter() {
+            let (low, high) = consumer
+                .fetch_watermarks(topic, partition, 5000)
+                .expect("failed to fetch the high-water mark");
+            let mut state = state.borrow_mut();
+            let next = state.offsets.get(&partition).cloned().unwrap_or(low);
+            state.end_offsets.insert(partition, high);
+            if next >= high {
+                state.finish(partition);
+            }
+        }
+    }
+
+    let mut idle: IdleCheck = match settings.assignment {
+        Assignment::Manual => {
+            let mut partition_list = TopicPartitionList::new();
+            for partition in my_partitions {
+                match state.borrow().offsets.get(&partition) {
+                    Some(&offset) => {
+                        let offset = Offset::Offset(offset);
+                        partition_list.add_partition_offset(topic, partition, offset);
+                    }
+                    None => {
+                        partition_list.add_partition(topic, partition);
+                    }
+                }
+            }
+            consumer.assign(&partition_list).expect("error in assigning partition list");
+
+            println!(
+                "[kafka-consumer] W{} assigned to {} partitions of topic \"{}\"",
+                index,
+                partition_list.count(),
+                topic
+            );
+
+            // the assignment never changes
+            Box::new(|_| false)
+        }
+        Assignment::Group => {
+            if !state.borrow().offsets.is_empty() {
+                println!("[kafka-consumer] restored offsets ignored with the group assignment");
+                state.borrow_mut().offsets.clear();
+            }
+
+            consumer.subscribe(&[topic]).expect("Failed to subscribe to topic");
+            println!("[kafka-consumer] W{} subscribed to topic \"{}\"", index, topic);
+
+            let state = state.clone();
+            let checkpointer = checkpointer.clone();
+            let idle_release = Duration::from_millis(settings.idle_release_ms);
+            let mut last_assigned = Instant::now();
+            let mut last_check = Instant::now();
+
+            Box::new(move |consumer| {
+                // querying the assignment is not free, do it at most once per second
+                if last_check.elapsed() < Duration::from_secs(1) {
+                    return false;
+                }
+                last_check = Instant::now();
+
+                let partitions = consumer
+                    .assignment()
+                    .map(|list| list.elements().iter().map(|e| e.partition()).collect())
+                    .unwrap_or(Vec::new());
+                // track the partitions currently assigned only
+                let mut state = state.borrow_mut();
+                let unfinished = partitions
+                    .iter()
+                    .filter(|p| !state.finished.contains(*p))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                state.watermarks.assigned(&unfinished);
+                state.partitions = partitions.clone();
+
+                if !partitions.is_empty() {
+                    last_assigned = Instant::now();
+                    false
+                } else if last_assigned.elapsed() >= idle_release {
+                    println!("[kafka-consumer] W{} has no partitions, leaving the group", index);
+                    consumer.unsubscribe();
+                    checkpointer.retire("KafkaSource");
+                    true
+                } else {
+                    false
+                }
+            })
+        }
+    };
+
+    let tick_state = state.clone();
+    let end_idle = Duration::from_millis(settings.end_idle_ms);
+    kafka_source(
+        scope,
+        "KafkaRecordSourceStream",
+        consumer,
+        probe.clone(),
+        move |consumer, capability| {
+            let release = idle(consumer);
+            let mut state = tick_state.borrow_mut();
+            if end_idle > Duration::from_millis(0) && state.last_record.elapsed() >= end_idle {
+                println!(
+                    "[kafka-consumer] W{} no records for {:?}, end of stream",
+                    index, end_idle
+                );
+                for partition in state.partitions.clone() {
+                    state.finish(partition);
+                }
+            }
+            // idle partitions might not hold back the time anymore
+            state.advance(capability);
+            state.is_over(index) || release
+        },
+        move |message, capability, output| {
+            let mut state = state.borrow_mut();
+            let partition = message.partition();
+            state.offsets.insert(partition, message.offset() + 1);
+            state.last_record = Instant::now();
+
+            let bytes = match message.payload() {
+                Some(bytes) => bytes,
+                None => return false,
             };
 
-            let time = *capability.time();
-            if timestamp - *MAX_DELAY_SEC > time {
-                // downgrade the capability by considering the event timestamp
-                // and the maximum bounded delay
-                capability.downgrade(&(timestamp - *MAX_DELAY_SEC));
+            // If the bytes are utf8, decode the message and send.
+            if let Ok(text) = std::str::from_utf8(bytes) {
+                let timestamp = match wire::decode(text) {
+                    // use watermark to downgrade capabilities
+                    Message::Watermark(timestamp) => Some(timestamp),
+                    // the producer sent the whole stream of the partition
+                    Message::End => {
+                        state.finish(partition);
+                        None
+                    }
+                    // forward only real events
+                    Message::Event(record, timestamp) => {
+                        output.session(capability).give(record);
+                        timestamp
+                    }
+                };
+
+                // downgrade the capability by considering the event timestamp,
+                // the maximum bounded delay and the progress of the other partitions
+                if !state.finished.contains(&partition) {
+                    state.watermarks.observe(partition, timestamp);
+                }
+                state.advance(capability);
+
+                // the records present at startup have been consumed
+                let end = state.end_offsets.get(&partition).cloned();
+                if end.map_or(false, |end| message.offset() + 1 >= end) {
+                    state.finish(partition);
+                }
+
+                state.is_over(index)
+            } else {
+                true
             }
+        },
+    )
+}
 
-            false
-        } else {
-            true
+/// state of the source shared by the message logic and the idle check
+struct SourceState {
+    checkpoint:  OperatorCheckpoint,
+    // partition --> offset of the next record to consume
+    offsets:     HashMap<i32, i64>,
+    watermarks:  PartitionWatermarks,
+    // partitions currently consumed by the worker
+    partitions:  Vec<i32>,
+    // partition --> high-water mark at startup, if the stream ends there
+    end_offsets: HashMap<i32, i64>,
+    // partitions whose stream is over
+    finished:    HashSet<i32>,
+    last_record: Instant,
+    ended:       bool,
+}
+
+impl SourceState {
+    /// the stream of the partition is over, it does not hold back the time anymore
+    fn finish(&mut self, partition: i32) {
+        if self.finished.insert(partition) {
+            println!("[kafka-consumer] end of stream of partition {}", partition);
+            self.watermarks.remove(partition);
+        }
+    }
+
+    /// whether the stream of all the partitions of the worker is over;
+    /// the first time it is, the offsets are not checkpointed anymore
+    fn is_over(&mut self, index: usize) -> bool {
+        if !self.ended
+            && !self.partitions.is_empty()
+            && self.partitions.iter().all(|p| self.finished.contains(p))
+        {
+            println!("[kafka-consumer] W{} end of stream, releasing its capability", index);
+            self.checkpoint.retire();
+            self.ended = true;
         }
-    })
+        self.ended
+    }
+
+    /// downgrade the capability to the low watermark of the partitions
+    fn advance(&mut self, capability: &mut Capability<u64>) {
+        let time = *capability.time();
+        if let Some(watermark) = self.watermarks.low_watermark().filter(|w| *w > time) {
+            capability.downgrade(&watermark);
+            // all the records consumed so far belong to the epoch
+            if let Some(epoch) = self.checkpoint.downgraded(time, watermark) {
+                self.checkpoint.save(epoch, &self.offsets);
+            }
+        }
+    }
+}
+
+/// ids of the partitions of the topic, from the metadata of the cluster
+fn topic_partitions(consumer: &BaseConsumer<EmptyConsumerContext>, topic: &str) -> Vec<i32> {
+    let metadata =
+        consumer.fetch_metadata(Some(topic), 5000).expect("failed to fetch the topic metadata");
+    let mut partitions = metadata
+        .topics()
+        .iter()
+        .filter(|t| t.name() == topic)
+        .flat_map(|t| t.partitions().iter().map(|p| p.id()))
+        .collect::<Vec<_>>();
+    partitions.sort();
+    partitions
 }
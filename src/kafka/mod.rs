@@ -1,2 +1,6 @@
 pub mod consumer;
+pub mod producer;
+pub mod settings;
+pub mod sink;
 pub mod source;
+pub mod watermarks;
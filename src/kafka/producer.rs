@@ -0,0 +1,45 @@
+use timely::dataflow::{Scope, Stream};
+use timely::Data;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, DefaultProducerContext};
+
+use super::settings::{self, KafkaSettings};
+use super::sink::kafka_sink;
+use crate::sink::json::JsonRecord;
+use crate::sink::Keyed;
+
+/// publish the output of a query on the requested topic.
+/// Every output is split in one message for each key (e.g. post id or person id),
+/// whose payload is the JSON record also written by the `json_sink`.
+pub fn json_sink<G, D>(
+    stream: &Stream<G, D>,
+    settings: &KafkaSettings,
+    topic: &str,
+    query: usize,
+    worker_id: usize,
+) where
+    G: Scope<Timestamp = u64>,
+    D: Data + Keyed,
+{
+    let mut producer_config = ClientConfig::new();
+    producer_config.set("message.timeout.ms", "5000");
+    settings::apply(settings, &mut producer_config);
+
+    let producer: BaseProducer<DefaultProducerContext> =
+        producer_config.create().expect("Producer creation error");
+
+    println!("[kafka-producer] publishing output of query {} to topic \"{}\"", query, topic);
+
+    kafka_sink(stream, "KafkaJsonSink", producer, topic.to_string(), move |out, &timestamp| {
+        out.keyed()
+            .into_iter()
+            .map(|(key, value)| {
+                let record =
+                    JsonRecord { query: query, worker: worker_id, timestamp: timestamp, data: value };
+                let payload = serde_json::to_string(&record).expect("failed to serialize record");
+                (key.map(|k| k.to_string()), payload)
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,10 @@
+use rdkafka::config::ClientConfig;
+
+pub use dspa_common::kafka_settings::{Assignment, KafkaSettings};
+
+/// set brokers, security options and extra properties on a client configuration
+pub fn apply(settings: &KafkaSettings, config: &mut ClientConfig) {
+    for (key, value) in settings.client_properties() {
+        config.set(&key, &value);
+    }
+}
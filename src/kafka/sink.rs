@@ -0,0 +1,58 @@
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::Operator;
+use timely::dataflow::{Scope, Stream};
+use timely::Data;
+
+use rdkafka::producer::{BaseProducer, BaseRecord, ProducerContext};
+
+const MAX_SEND_ATTEMPTS: usize = 10;
+
+// mirror of `kafka_source`: publish records on a kafka topic
+pub fn kafka_sink<C, G, D, L>(
+    stream: &Stream<G, D>,
+    name: &str,
+    producer: BaseProducer<C>,
+    topic: String,
+    logic: L,
+) where
+    C: ProducerContext<DeliveryOpaque = ()> + 'static,
+    G: Scope,
+    D: Data,
+    L: Fn(&D, &G::Timestamp) -> Vec<(Option<String>, String)> + 'static,
+{
+    let mut buf = Vec::new();
+
+    stream.sink(Pipeline, name, move |input| {
+        input.for_each(|time, data| {
+            data.swap(&mut buf);
+
+            for datum in buf.drain(..) {
+                // use caller-provided function to get the (key, payload) pairs to send
+                for (key, payload) in logic(&datum, time.time()) {
+                    let mut record = BaseRecord::to(&topic).payload(&payload);
+                    if let Some(key) = &key {
+                        record = record.key(key);
+                    }
+                    let mut attempts = 0;
+                    // the local queue might be full, serve delivery reports and retry
+                    while let Err((err, rec)) = producer.send(record) {
+                        attempts += 1;
+                        if attempts == MAX_SEND_ATTEMPTS {
+                            println!("Kafka error {:?}, dropping record with key {:?}", err, key);
+                            break;
+                        }
+                        producer.poll(100);
+                        record = rec;
+                    }
+                }
+            }
+        });
+
+        producer.poll(0);
+
+        if input.frontier().frontier().is_empty() {
+            // the stream is complete, make sure everything is delivered
+            producer.flush(5000);
+        }
+    });
+}
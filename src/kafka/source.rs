@@ -1,25 +1,49 @@
+use std::collections::{HashMap, VecDeque};
+
 use timely::dataflow::channels::pushers::Tee;
 use timely::dataflow::operators::generic::OutputHandle;
 use timely::dataflow::operators::Capability;
-use timely::dataflow::{Scope, Stream};
+use timely::dataflow::{ProbeHandle, Scope, Stream};
+use timely::progress::Timestamp;
 use timely::Data;
 
-use rdkafka::consumer::{BaseConsumer, ConsumerContext};
-use rdkafka::Message;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer, ConsumerContext};
+use rdkafka::message::BorrowedMessage;
+use rdkafka::{Message, Offset, TopicPartitionList};
+
+// (topic, partition) --> (time the records were emitted at, offset of the last of them)
+type PendingOffsets<T> = HashMap<(String, i32), VecDeque<(T, i64)>>;
 
 // mostly copy paste from timely repo
-pub fn kafka_source<C, G, D, L>(
+// `logic` gets the whole message, so that it can keep track of partitions and offsets.
+//
+// Offsets are committed once the dataflow is done with the records:
+// the source remembers the time each record was emitted at, and when the frontier
+// of `probe` has passed that time the offset is committed to the consumer group.
+// Restarting from the committed offsets never skips a record that was not
+// completely processed (at-least-once). Once the capability is released, the source
+// keeps running until the offsets of its last records are committed, and whatever
+// the dataflow completed is committed synchronously when the source is dropped.
+//
+// `tick` is called on every activation after polling, it can downgrade the capability
+// even if no message arrived (e.g. when partitions become idle) and returns whether the source
+// has nothing left to consume (e.g. no partitions are assigned to the consumer),
+// in which case the capability is released.
+pub fn kafka_source<C, G, D, I, L>(
     scope: &G,
     name: &str,
     consumer: BaseConsumer<C>,
-    logic: L,
+    probe: ProbeHandle<G::Timestamp>,
+    mut tick: I,
+    mut logic: L,
 ) -> Stream<G, D>
 where
     C: ConsumerContext + 'static,
     G: Scope,
     D: Data,
-    L: Fn(
-            &[u8],
+    I: FnMut(&BaseConsumer<C>, &mut Capability<G::Timestamp>) -> bool + 'static,
+    L: FnMut(
+            &BorrowedMessage,
             &mut Capability<G::Timestamp>,
             &mut OutputHandle<G::Timestamp, D, Tee<G::Timestamp, D>>,
         ) -> bool
@@ -29,6 +53,8 @@ where
     source(scope, name, move |capability, info| {
         let activator = scope.activator_for(&info.address[..]);
         let mut cap = Some(capability);
+        let mut committer =
+            Committer { consumer: consumer, probe: probe, pending: HashMap::new() };
 
         // define a closure to call repeatedly.
         move |output| {
@@ -41,22 +67,80 @@ where
                 // Repeatedly interrogate Kafka for [u8] messages.
                 // Cease only when Kafka stops returning new data.
                 // Could cease earlier, if we had a better policy.
-                while let Some(result) = consumer.poll(0) {
+                while let Some(result) = committer.consumer.poll(0) {
                     // If valid data back from Kafka
                     if let Ok(message) = result {
-                        // Attempt to interpret bytes as utf8  ...
-                        if let Some(payload) = message.payload() {
-                            complete = logic(payload, &mut capability, output) || complete;
+                        let time = capability.time().clone();
+                        let key = (message.topic().to_string(), message.partition());
+                        let offsets = committer.pending.entry(key).or_insert(VecDeque::new());
+                        match offsets.back_mut() {
+                            Some((last_time, last_offset)) if *last_time == time => {
+                                *last_offset = message.offset()
+                            }
+                            _ => offsets.push_back((time, message.offset())),
                         }
+
+                        complete = logic(&message, &mut capability, output) || complete;
                     } else {
                         println!("Kafka error");
                     }
                 }
+
+                complete = tick(&committer.consumer, &mut capability) || complete;
             }
 
             if complete {
                 cap = None;
             }
+
+            if cap.is_some() {
+                committer.commit_completed(CommitMode::Async);
+            } else if !committer.pending.is_empty() {
+                // run again until the dataflow is done with the last records
+                committer.commit_completed(CommitMode::Sync);
+                activator.activate();
+            }
         }
     })
 }
+
+/// consumer of the source, along with the offsets of the records not completed yet
+struct Committer<C: ConsumerContext, T: Timestamp> {
+    consumer: BaseConsumer<C>,
+    probe:    ProbeHandle<T>,
+    pending:  PendingOffsets<T>,
+}
+
+impl<C: ConsumerContext, T: Timestamp> Committer<C, T> {
+    /// commit the offsets of the records at times completed by the dataflow
+    fn commit_completed(&mut self, mode: CommitMode) {
+        let probe = &self.probe;
+        let mut partition_list = TopicPartitionList::new();
+        for ((topic, partition), offsets) in self.pending.iter_mut() {
+            let mut completed = None;
+            while offsets.front().map_or(false, |(time, _)| !probe.less_equal(time)) {
+                completed = offsets.pop_front().map(|(_, offset)| offset);
+            }
+            if let Some(offset) = completed {
+                // the committed offset is the one of the next record to consume
+                partition_list.add_partition_offset(topic, *partition, Offset::Offset(offset + 1));
+            }
+        }
+        self.pending.retain(|_, offsets| !offsets.is_empty());
+
+        if partition_list.count() > 0 {
+            if let Err(err) = self.consumer.commit(&partition_list, mode) {
+                println!("[kafka-source] failed to commit offsets: {}", err);
+            }
+        }
+    }
+}
+
+impl<C: ConsumerContext, T: Timestamp> Drop for Committer<C, T> {
+    /// the dataflow may be torn down before the source runs again, commit what it completed
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            self.commit_completed(CommitMode::Sync);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    static ref MAX_DELAY_SEC: u64 = SETTINGS.get::<u64>("MAX_DELAY_SEC").unwrap();
+}
+
+/// Low watermark of each partition consumed by a worker.
+///
+/// Records within a partition are delayed by at most MAX_DELAY_SEC, but partitions
+/// are consumed at different speeds: the time of the source can only advance to the
+/// minimum of the partition watermarks, otherwise a fast partition would advance it
+/// past the events still to be consumed from a slower one.
+///
+/// A partition that did not return any record for `idle_timeout` is considered idle
+/// and does not hold back the time until it returns records again.
+pub struct PartitionWatermarks {
+    partitions:   HashMap<i32, PartitionWatermark>,
+    idle_timeout: Duration,
+}
+
+struct PartitionWatermark {
+    // None until the first timestamp of the partition has been seen
+    watermark:   Option<u64>,
+    last_record: Instant,
+}
+
+impl PartitionWatermark {
+    fn new() -> PartitionWatermark {
+        PartitionWatermark { watermark: None, last_record: Instant::now() }
+    }
+}
+
+impl PartitionWatermarks {
+    pub fn new(partitions: &[i32], idle_timeout: Duration) -> PartitionWatermarks {
+        PartitionWatermarks {
+            partitions:   partitions.iter().map(|&p| (p, PartitionWatermark::new())).collect(),
+            idle_timeout: idle_timeout,
+        }
+    }
+
+    /// a record has been consumed from the partition, with its event or watermark timestamp
+    pub fn observe(&mut self, partition: i32, timestamp: Option<u64>) {
+        let entry = self.partitions.entry(partition).or_insert_with(PartitionWatermark::new);
+        entry.last_record = Instant::now();
+        if let Some(timestamp) = timestamp {
+            let watermark = timestamp.saturating_sub(*MAX_DELAY_SEC);
+            entry.watermark = Some(entry.watermark.map_or(watermark, |w| w.max(watermark)));
+        }
+    }
+
+    /// the partitions assigned to the consumer changed (e.g. after a rebalance)
+    pub fn assigned(&mut self, partitions: &[i32]) {
+        self.partitions.retain(|p, _| partitions.contains(p));
+        for &partition in partitions {
+            self.partitions.entry(partition).or_insert_with(PartitionWatermark::new);
+        }
+    }
+
+    /// the partition is not consumed anymore (e.g. its stream is over)
+    pub fn remove(&mut self, partition: i32) { self.partitions.remove(&partition); }
+
+    /// minimum watermark across the partitions that are not idle;
+    /// None if one of them has no watermark yet, or if all of them are idle
+    pub fn low_watermark(&self) -> Option<u64> {
+        self.partitions
+            .values()
+            .filter(|p| p.last_record.elapsed() < self.idle_timeout)
+            .map(|p| p.watermark)
+            .min()?
+    }
+}
@@ -4,10 +4,17 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
+extern crate bincode;
 extern crate config;
+extern crate dspa_common;
 
+pub mod checkpoint;
 pub mod db;
 pub mod event;
+pub mod file;
 pub mod kafka;
 pub mod operators;
 pub mod percentile;
+pub mod sink;
+pub mod spill;
+pub mod wire;
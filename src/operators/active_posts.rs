@@ -1,55 +1,227 @@
-use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
 use timely::dataflow::{Scope, Stream};
 
 use colored::*;
 
-use crate::operators::window_notify::{Timestamp, WindowNotify};
+use crate::checkpoint::Checkpointer;
+use crate::operators::window_notify::{Amendment, Timestamp, WindowNotify};
+use crate::operators::windows::{Aggregator, WindowKind, Windows};
 
 const NOTIFICATION_FREQ: u64 = 30 * 60; // every 30 minutes
-const ACTIVE_WINDOW_SECONDS: u64 = 12 * 3600; // stats for the last 12 hours
+pub const ACTIVE_WINDOW_SECONDS: u64 = 12 * 3600; // stats for the last 12 hours
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    /// horizons of the windowed statistics (in seconds), always including the active window
+    static ref STATS_HORIZONS: Vec<u64> = {
+        let mut horizons: Vec<u64> = SETTINGS
+            .get::<String>("STATS_HORIZONS_HOURS")
+            .unwrap()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<u64>().unwrap() * 3600)
+            .collect();
+        horizons.push(ACTIVE_WINDOW_SECONDS);
+        horizons.sort();
+        horizons.dedup();
+        horizons
+    };
+    /// emit only the posts whose stats changed, and the ones that became inactive
+    pub static ref DIFF_OUTPUT: bool = {
+        match SETTINGS.get::<String>("ACTIVE_POSTS_OUTPUT").unwrap().as_ref() {
+            "full" => false,
+            "diff" => true,
+            other => panic!("unknown ACTIVE_POSTS_OUTPUT {:?}, expected full or diff", other),
+        }
+    };
+}
 
 /// Given a stream of StatUpdate (statistics updates) events,
 /// update the statistic for the associated post.
 ///
-/// Every 30 minutes, emit the statistics for all active posts.
-/// We do not discard post statistics when they become inactive.
+/// Every 30 minutes, emit the statistics for all active posts,
+/// i.e. the ones with some activity in the last 12 hours (a sliding window,
+/// see the `windows` module). We do not discard post statistics when they become inactive.
+/// Along with the all-time statistics, the ones restricted to the last 12 hours
+/// and to the other horizons of STATS_HORIZONS_HOURS are reported.
+/// Likes are counted along with the number of distinct likers, and their
+/// velocity (likes per hour) is reported for each horizon.
+///
+/// With ACTIVE_POSTS_OUTPUT = "diff" only the posts whose stats changed since
+/// the previous notification are emitted, along with the posts that became
+/// inactive, so that downstream consumers can maintain a view of the active posts.
+/// In this mode, amendments retract the diff emitted for the window, and carry the one
+/// including the late events.
 ///
 /// The windowing and out-of-order logic is handled by the
-/// generic `window_notify` operator.
+/// generic `window_notify` operator, the second stream amends the
+/// statistics already emitted when late events arrive.
 ///
 pub trait ActivePosts<G: Scope> {
-    fn active_posts(&self, worker_id: usize) -> Stream<G, HashMap<u64, Stats>>;
+    fn active_posts(
+        &self,
+        worker_id: usize,
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, HashMap<u64, PostStatus>>, Stream<G, Amendment<HashMap<u64, PostStatus>>>);
 }
 
 impl<G: Scope<Timestamp = u64>> ActivePosts<G> for Stream<G, StatUpdate> {
-    fn active_posts(&self, worker_id: usize) -> Stream<G, HashMap<u64, Stats>> {
+    fn active_posts(
+        &self,
+        worker_id: usize,
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, HashMap<u64, PostStatus>>, Stream<G, Amendment<HashMap<u64, PostStatus>>>) {
         self.window_notify(
             NOTIFICATION_FREQ,
             "ActivePosts",
             ActivePostsState::new(worker_id),
-            |state, stat_update, _| state.update_stats(&stat_update),
+            checkpointer,
+            |state, stat_update, next_notification_time| {
+                state.update_stats(&stat_update, next_notification_time)
+            },
             |state, timestamp| state.active_posts_stats(timestamp),
         )
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stats {
-    num_comments:  u64,
-    num_replies:   u64,
-    unique_people: HashSet<u64>,
+    pub num_comments:  u64,
+    pub num_replies:   u64,
+    pub unique_people: HashSet<u64>,
+    #[serde(default)]
+    pub num_likes:     u64,
+    // people that liked the post
+    #[serde(default)]
+    pub likers:        HashSet<u64>,
+    // timestamp of the latest event of the post
+    #[serde(default)]
+    pub last_activity: u64,
+    // horizon (in seconds) --> statistics of the last `horizon` seconds, only set in the output
+    // (never skipped when empty: the stats are checkpointed with bincode)
+    #[serde(default)]
+    pub windows:       BTreeMap<u64, WindowStats>,
 }
 
 impl Stats {
-    fn new() -> Stats { Stats { num_comments: 0, num_replies: 0, unique_people: HashSet::new() } }
+    fn new() -> Stats {
+        Stats {
+            num_comments:  0,
+            num_replies:   0,
+            unique_people: HashSet::new(),
+            num_likes:     0,
+            likers:        HashSet::new(),
+            last_activity: 0,
+            windows:       BTreeMap::new(),
+        }
+    }
     fn new_comment(&mut self) { self.num_comments += 1; }
     fn new_reply(&mut self) { self.num_replies += 1; }
+    fn new_like(&mut self, id: u64) {
+        self.num_likes += 1;
+        self.likers.insert(id);
+    }
     fn new_person(&mut self, id: u64) { self.unique_people.insert(id); }
 }
 
-pub fn dump_stats(stats: &HashMap<u64, Stats>, num_spaces: usize) {
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // sort people so that the output is deterministic
+        let mut people = self.unique_people.iter().collect::<Vec<_>>();
+        people.sort();
+        write!(
+            f,
+            "comments = {}, replies = {}, people = {:?}",
+            self.num_comments, self.num_replies, people
+        )
+    }
+}
+
+/// output of a post, in diff mode a post can also become inactive
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum PostStatus {
+    Active(Stats),
+    Inactive,
+}
+
+impl fmt::Display for PostStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostStatus::Active(stats) => write!(f, "{}", stats),
+            PostStatus::Inactive => write!(f, "inactive"),
+        }
+    }
+}
+
+/// statistics of a post restricted to a window
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WindowStats {
+    pub num_comments:  u64,
+    pub num_replies:   u64,
+    pub num_people:    usize,
+    pub num_likes:     u64,
+    pub num_likers:    usize,
+    // likes per hour over the window
+    pub like_velocity: f64,
+}
+
+/// aggregate of the updates of a post in a pane of the windowed statistics
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Engagement {
+    num_comments:  u64,
+    num_replies:   u64,
+    unique_people: HashSet<u64>,
+    num_likes:     u64,
+    likers:        HashSet<u64>,
+}
+
+impl Aggregator for Engagement {
+    type Input = StatUpdate;
+    type Output = WindowStats;
+
+    fn add(&mut self, stat_update: &StatUpdate) {
+        match stat_update.update_type {
+            StatUpdateType::Comment => self.num_comments += 1,
+            StatUpdateType::Reply => self.num_replies += 1,
+            StatUpdateType::Like => {
+                self.num_likes += 1;
+                self.likers.insert(stat_update.person_id);
+            }
+            StatUpdateType::Post => {}
+        }
+        self.unique_people.insert(stat_update.person_id);
+    }
+
+    fn merge(&mut self, other: &Engagement) {
+        self.num_comments += other.num_comments;
+        self.num_replies += other.num_replies;
+        self.unique_people.extend(other.unique_people.iter());
+        self.num_likes += other.num_likes;
+        self.likers.extend(other.likers.iter());
+    }
+
+    /// the like velocity depends on the length of the window, it is set by the caller
+    fn result(&self) -> WindowStats {
+        WindowStats {
+            num_comments:  self.num_comments,
+            num_replies:   self.num_replies,
+            num_people:    self.unique_people.len(),
+            num_likes:     self.num_likes,
+            num_likers:    self.likers.len(),
+            like_velocity: 0.0,
+        }
+    }
+}
+
+pub fn dump_stats<V: fmt::Debug>(stats: &HashMap<u64, V>, num_spaces: usize) {
     let spaces = " ".repeat(num_spaces);
     println!("{}---- stats", spaces);
     for (post_id, stats) in stats {
@@ -58,7 +230,7 @@ pub fn dump_stats(stats: &HashMap<u64, Stats>, num_spaces: usize) {
     println!("{}----", spaces);
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum StatUpdateType {
     Post,
     Comment,
@@ -67,7 +239,7 @@ pub enum StatUpdateType {
 }
 
 /// event type sent by the `post_trees` operator
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct StatUpdate {
     pub update_type: StatUpdateType,
     pub post_id:     u64,
@@ -79,22 +251,32 @@ impl Timestamp for StatUpdate {
     fn timestamp(&self) -> u64 { self.timestamp }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct ActivePostsState {
     worker_id: usize,
-    // post ID --> timestamp of last event associated with it
-    last_timestamp: HashMap<u64, u64>,
-    // post ID --> stats
-    stats: HashMap<u64, Stats>,
+    // post ID --> updates of the post, over the longest horizon
+    recent:    Windows<u64, Engagement>,
+    // post ID --> all-time stats
+    stats:     HashMap<u64, Stats>,
+    // posts updated since the previous notification
+    #[serde(default)]
+    updated:   HashSet<u64>,
+    // post ID --> windowed stats, for the posts active at the previous notification
+    #[serde(default)]
+    emitted:   HashMap<u64, BTreeMap<u64, WindowStats>>,
 }
 
 /// State associated with the `active_posts` operator
 impl ActivePostsState {
     fn new(worker_id: usize) -> ActivePostsState {
+        let size = *STATS_HORIZONS.last().unwrap();
+        let kind = WindowKind::Sliding { size: size, slide: NOTIFICATION_FREQ };
         ActivePostsState {
-            worker_id:      worker_id,
-            last_timestamp: HashMap::<u64, u64>::new(),
-            stats:          HashMap::<u64, Stats>::new(),
+            worker_id: worker_id,
+            recent:    Windows::new(kind),
+            stats:     HashMap::<u64, Stats>::new(),
+            updated:   HashSet::new(),
+            emitted:   HashMap::new(),
         }
     }
 
@@ -109,7 +291,6 @@ impl ActivePostsState {
             )
         );
         println!("{}", "  Current stats".bold().blue());
-        println!("    last_timestamp -- {:?}", self.last_timestamp);
         dump_stats(&self.stats, 4);
         // println!("{}", "  Next stats".bold().blue());
         // println!("    next_last_timestamp -- {:?}", self.next_last_timestamp);
@@ -117,46 +298,70 @@ impl ActivePostsState {
     }
 
     /// update statistics for the post id associated with the update
-    fn update_stats(&mut self, stat_update: &StatUpdate) {
+    fn update_stats(&mut self, stat_update: &StatUpdate, next_notification_time: u64) {
         let post_id = stat_update.post_id;
         let timestamp = stat_update.timestamp;
 
-        // update last_timestamp
-        match self.last_timestamp.get(&post_id) {
-            Some(&prev) => self.last_timestamp.insert(post_id, max(prev, timestamp)),
-            None => self.last_timestamp.insert(post_id, timestamp),
-        };
+        // update the windowed stats, panes end at the notification times
+        self.recent.align(next_notification_time);
+        self.recent.insert(post_id, timestamp, stat_update);
 
+        // updates of a post might be applied before the post itself,
+        // when they belong to an earlier window
+        let stats = self.stats.entry(post_id).or_insert(Stats::new());
         match stat_update.update_type {
-            StatUpdateType::Comment => self.stats.get_mut(&post_id).unwrap().new_comment(),
-            StatUpdateType::Reply => self.stats.get_mut(&post_id).unwrap().new_reply(),
-            _ => {} // nothing to do for posts and likes
+            StatUpdateType::Comment => stats.new_comment(),
+            StatUpdateType::Reply => stats.new_reply(),
+            StatUpdateType::Like => stats.new_like(stat_update.person_id),
+            StatUpdateType::Post => {} // nothing to do for posts
         }
 
-        // update unique people set
-        self.stats.entry(post_id).or_insert(Stats::new()).new_person(stat_update.person_id);
+        // update unique people set and latest activity
+        stats.new_person(stat_update.person_id);
+        stats.last_activity = stats.last_activity.max(timestamp);
+        self.updated.insert(post_id);
     }
 
     /// emit statistics for the active posts
-    fn active_posts_stats(&mut self, cur_timestamp: u64) -> HashMap<u64, Stats> {
-        let active_posts = self
-            .last_timestamp
+    fn active_posts_stats(&mut self, cur_timestamp: u64) -> HashMap<u64, PostStatus> {
+        // discard the updates older than the longest horizon
+        self.recent.expire(cur_timestamp);
+        let aggregates = STATS_HORIZONS
             .iter()
-            .filter_map(|(&post_id, &last_t)| {
-                if last_t >= cur_timestamp - ACTIVE_WINDOW_SECONDS {
-                    Some(post_id)
-                } else {
-                    None
-                }
-            })
-            .collect::<HashSet<_>>();
-
-        let active_posts_stats = self
-            .stats
-            .clone()
-            .into_iter()
-            .filter(|&(id, _)| active_posts.contains(&id))
-            .collect::<HashMap<_, _>>();
+            .map(|&horizon| (horizon, self.recent.aggregate_over(cur_timestamp, horizon)))
+            .collect::<BTreeMap<_, _>>();
+
+        // posts with some activity in the last 12 hours --> their windowed stats
+        let mut windows = HashMap::<u64, BTreeMap<u64, WindowStats>>::new();
+        for &id in aggregates[&ACTIVE_WINDOW_SECONDS].keys() {
+            let post_windows = windows.entry(id).or_insert_with(BTreeMap::new);
+            for (horizon, aggregate) in aggregates.iter() {
+                let window_stats = aggregate.get(&id).map(|engagement| engagement.result());
+                let mut window_stats = window_stats.unwrap_or_default();
+                window_stats.like_velocity =
+                    window_stats.num_likes as f64 / (*horizon as f64 / 3600.0);
+                post_windows.insert(*horizon, window_stats);
+            }
+        }
+
+        // only copy the stats of the active posts (that changed, in diff mode)
+        let mut active_posts_stats = HashMap::new();
+        for (id, post_windows) in windows.iter() {
+            let changed = self.updated.contains(id) || self.emitted.get(id) != Some(post_windows);
+            if let (Some(stats), true) = (self.stats.get(id), changed || !*DIFF_OUTPUT) {
+                let mut stats = stats.clone();
+                stats.windows = post_windows.clone();
+                active_posts_stats.insert(*id, PostStatus::Active(stats));
+            }
+        }
+
+        if *DIFF_OUTPUT {
+            for id in self.emitted.keys().filter(|id| !windows.contains_key(id)) {
+                active_posts_stats.insert(*id, PostStatus::Inactive);
+            }
+            self.emitted = windows;
+        }
+        self.updated.clear();
 
         active_posts_stats
     }
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use timely::dataflow::channels::pact::Exchange;
+use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
+use timely::dataflow::operators::Capability;
+use timely::dataflow::{Scope, Stream};
+
+use colored::*;
+
+use crate::checkpoint::{Checkpointer, Stash};
+use crate::event::Event;
+use crate::operators::post_trees::{EVICTION_FREQ, INACTIVE_TREE_SEC, SPILL_DIR};
+use crate::spill::SpillStore;
+
+// replies to comments nested deeper than this may be dropped while waiting for them
+const MAX_NESTING: u64 = 1000;
+
+/// Messages handled by the distributed index of the comments
+#[derive(Clone, Debug)]
+pub enum IndexMessage {
+    /// the comment belongs to the tree of the root post
+    Entry { comment_id: u64, root_post_id: u64 },
+    /// reply (or like) to a comment, waiting for the root post of the comment
+    Reply(Event),
+}
+
+impl abomonation::Abomonation for IndexMessage {}
+
+impl IndexMessage {
+    /// the message is handled by the worker owning this comment id
+    fn comment_id(&self) -> u64 {
+        match self {
+            IndexMessage::Entry { comment_id, root_post_id: _ } => *comment_id,
+            IndexMessage::Reply(event) => event.target_id(),
+        }
+    }
+}
+
+/// Distributed index from comment id to the root post id of its tree.
+///
+/// The comments are partitioned among the workers by comment id: each worker
+/// stores the root post of its comments, and receives the replies to them.
+/// A reply is emitted on the first output stream along with the root post of
+/// the comment it replies to, so that it can be routed to the worker owning
+/// the post tree. The entry of the reply itself is emitted on the second output
+/// stream, it has to be fed back to the index (replies can be nested).
+/// Likes to comments are routed the same way, they have no entry.
+///
+/// Replies (and likes) to comments not indexed yet wait for them, and are dropped
+/// (and counted) once the frontier of the input has passed their timestamp by
+/// MAX_NESTING seconds: a comment is received at a time not later than its
+/// timestamp, and the entry of a reply is fed back one second after it has been
+/// resolved, so the entry of a comment nested d levels deep arrives at most d seconds
+/// after its timestamp. Replies are not older than their comment, so the entries
+/// of the comments they reply to have arrived by then.
+/// The dropped replies are reported once the input is exhausted.
+///
+/// The entries of a tree are evicted along with the post trees (see POST_TREES_INACTIVE_HOURS)
+/// once the worker has not seen any activity in the tree for that long, i.e. no entries
+/// nor replies to its comments. If POST_TREES_SPILL_DIR is set, they are spilled to disk,
+/// and looked up there by the replies to an unknown comment.
+///
+/// If checkpointing is enabled, the index and the waiting replies are saved
+/// at the end of every epoch (see the `checkpoint` module).
+///
+pub trait CommentIndex<G: Scope> {
+    fn comment_index(
+        &self,
+        worker_id: usize,
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, (Event, u64)>, Stream<G, IndexMessage>);
+}
+
+impl<G: Scope<Timestamp = u64>> CommentIndex<G> for Stream<G, IndexMessage> {
+    fn comment_index(
+        &self,
+        worker_id: usize,
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, (Event, u64)>, Stream<G, IndexMessage>) {
+        let mut checkpoint = checkpointer.register("CommentIndex");
+        let mut state = match checkpoint.restore::<CommentIndexState>() {
+            Some(mut state) => {
+                state.init_waiting();
+                state.spill = spill_store(worker_id, true);
+                state
+            }
+            None => CommentIndexState::new(worker_id),
+        };
+
+        let mut builder = OperatorBuilder::new("CommentIndex".to_owned(), self.scope());
+
+        let exchange = Exchange::new(|message: &IndexMessage| message.comment_id());
+        let mut input = builder.new_input(self, exchange);
+
+        let (mut reply_output, reply_stream) = builder.new_output();
+        let (mut entry_output, entry_stream) = builder.new_output();
+
+        builder.build(move |_| {
+            // messages of the next epoch, waiting for the checkpoint of the current one
+            let mut stash = Stash::new();
+            let mut reported = false;
+
+            move |frontiers| {
+                let mut reply_handle = reply_output.activate();
+                let mut entry_handle = entry_output.activate();
+
+                let mut process = |state: &mut CommentIndexState,
+                                   time: Capability<u64>,
+                                   messages: Vec<IndexMessage>| {
+                    state.process_messages(messages, *time.time());
+
+                    let mut reply_session = reply_handle.session(&time);
+                    for resolved in state.resolved_replies.drain(..) {
+                        reply_session.give(resolved);
+                    }
+
+                    let mut entry_session = entry_handle.session(&time);
+                    for entry in state.new_entries.drain(..) {
+                        entry_session.give(entry);
+                    }
+                };
+
+                input.for_each(|time, data| {
+                    let mut messages = Vec::new();
+                    data.swap(&mut messages);
+
+                    if checkpoint.is_ahead(*time.time()) {
+                        stash.push(time.retain(), messages);
+                    } else {
+                        process(&mut state, time.retain(), messages);
+                    }
+                });
+
+                checkpoint.align(frontiers[0].frontier(), &mut stash, &mut state, &mut process);
+
+                state.clean_waiting(frontiers[0].frontier());
+
+                // the input is exhausted (e.g. end of the stream), report the counters once
+                if frontiers[0].frontier().is_empty() && !reported {
+                    state.report();
+                    reported = true;
+                }
+            }
+        });
+
+        (reply_stream, entry_stream)
+    }
+}
+
+/// comments of a tree owned by the worker, to evict them once the tree is inactive
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tree {
+    last_activity: u64,
+    comments:      Vec<u64>,
+}
+
+/// store of the evicted entries of the worker, if spilling is enabled:
+/// the entries spilled by a previous run are only kept when its state is restored
+fn spill_store(worker_id: usize, restored: bool) -> Option<SpillStore<u64, u64>> {
+    if SPILL_DIR.is_empty() {
+        return None;
+    }
+    let dir = PathBuf::from(&*SPILL_DIR).join(format!("index-w{}", worker_id));
+    if restored {
+        Some(SpillStore::open(dir))
+    } else {
+        Some(SpillStore::new(dir))
+    }
+}
+
+/// State associated with the `comment_index` operator
+#[derive(Serialize, Deserialize)]
+struct CommentIndexState {
+    worker_id:        usize,
+    // comment ID --> root post ID, for the comments owned by this worker
+    root_of:          HashMap<u64, u64>,
+    // root post ID --> comments of the tree in `root_of`
+    #[serde(default)]
+    trees:            HashMap<u64, Tree>,
+    // time of the next look for inactive trees
+    #[serde(default)]
+    next_eviction:    u64,
+    // evicted entries, looked up by the replies to unknown comments
+    #[serde(skip)]
+    spill:            Option<SpillStore<u64, u64>>,
+    // comment ID --> replies waiting for the comment to be indexed
+    waiting:          HashMap<u64, Vec<Event>>,
+    // waiting replies dropped so far because their comment never arrived in time
+    #[serde(skip)]
+    num_dropped:      usize,
+    // replies resolved, along with their root post ID
+    #[serde(skip)]
+    resolved_replies: Vec<(Event, u64)>,
+    // entries of the resolved replies, to be fed back to the index
+    #[serde(skip)]
+    new_entries:      Vec<IndexMessage>,
+}
+
+impl CommentIndexState {
+    fn new(worker_id: usize) -> CommentIndexState {
+        CommentIndexState {
+            worker_id:        worker_id,
+            root_of:          HashMap::new(),
+            trees:            HashMap::new(),
+            next_eviction:    0,
+            spill:            spill_store(worker_id, false),
+            waiting:          HashMap::new(),
+            num_dropped:      0,
+            resolved_replies: Vec::new(),
+            new_entries:      Vec::new(),
+        }
+    }
+
+    /// ids of the events are not part of the checkpoints, fill them in after a restore
+    fn init_waiting(&mut self) {
+        for replies in self.waiting.values_mut() {
+            *replies = replies.drain(..).map(|reply| reply.init()).collect();
+        }
+    }
+
+    /// process a batch of messages received at `time`,
+    /// the resolved replies and their entries are appended to the pending lists
+    fn process_messages(&mut self, messages: Vec<IndexMessage>, time: u64) {
+        for message in messages {
+            match message {
+                IndexMessage::Entry { comment_id, root_post_id } => {
+                    self.insert_entry(comment_id, root_post_id, time);
+                    if let Some(replies) = self.waiting.remove(&comment_id) {
+                        for reply in replies {
+                            self.resolve(reply, root_post_id, time);
+                        }
+                    }
+                }
+                IndexMessage::Reply(reply) => match self.lookup(reply.target_id(), time) {
+                    Some(root_post_id) => self.resolve(reply, root_post_id, time),
+                    None => self.waiting.entry(reply.target_id()).or_insert(Vec::new()).push(reply),
+                },
+            }
+        }
+
+        // forget the trees that became inactive
+        self.evict_trees(time);
+    }
+
+    /// index a comment of the tree
+    fn insert_entry(&mut self, comment_id: u64, root_post_id: u64, time: u64) {
+        let tree = self.trees.entry(root_post_id).or_insert(Tree::default());
+        tree.last_activity = tree.last_activity.max(time);
+        tree.comments.push(comment_id);
+        self.root_of.insert(comment_id, root_post_id);
+    }
+
+    /// root post of the comment, looking into the evicted entries
+    /// if it is not in memory (the entry is then restored)
+    fn lookup(&mut self, comment_id: u64, time: u64) -> Option<u64> {
+        if let Some(&root_post_id) = self.root_of.get(&comment_id) {
+            return Some(root_post_id);
+        }
+        let root_post_id = self.spill.as_ref()?.lookup(&comment_id)?;
+        self.insert_entry(comment_id, root_post_id, time);
+        Some(root_post_id)
+    }
+
+    /// every EVICTION_FREQ, remove from memory the entries of the trees without
+    /// activity in the last INACTIVE_TREE_SEC (and spill them to disk, if enabled)
+    fn evict_trees(&mut self, time: u64) {
+        // eviction is disabled
+        if *INACTIVE_TREE_SEC == 0 || time < self.next_eviction {
+            return;
+        }
+        self.next_eviction = time + EVICTION_FREQ;
+
+        let inactive = self
+            .trees
+            .iter()
+            .filter(|(_, tree)| tree.last_activity + *INACTIVE_TREE_SEC <= time)
+            .map(|(&root_post_id, _)| root_post_id)
+            .collect::<Vec<_>>();
+
+        let mut evicted = Vec::new();
+        for root_post_id in inactive {
+            for comment_id in self.trees.remove(&root_post_id).unwrap().comments {
+                // the comment may have been indexed again in another tree since (a duplicate)
+                if self.root_of.get(&comment_id) == Some(&root_post_id) {
+                    self.root_of.remove(&comment_id);
+                    evicted.push((comment_id, root_post_id));
+                }
+            }
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            spill.write(evicted);
+        }
+    }
+
+    /// the root post of the reply is known, index the reply itself (likes have no id)
+    fn resolve(&mut self, reply: Event, root_post_id: u64, time: u64) {
+        // a reply keeps the tree active
+        if let Some(tree) = self.trees.get_mut(&root_post_id) {
+            tree.last_activity = tree.last_activity.max(time);
+        }
+
+        if let Some(id) = reply.id() {
+            let comment_id = id.u64();
+            self.new_entries.push(IndexMessage::Entry { comment_id, root_post_id });
+        }
+        self.resolved_replies.push((reply, root_post_id));
+    }
+
+    /// drop the waiting replies whose comment cannot be indexed anymore, i.e. the ones
+    /// such that the frontier has passed the latest time their comment can be fed back at
+    fn clean_waiting(&mut self, frontier: &[u64]) {
+        if self.waiting.is_empty() {
+            return;
+        }
+        // every reply can be dropped once the input is exhausted
+        let time = frontier.iter().min().cloned().unwrap_or(std::u64::MAX);
+
+        let num_before = self.waiting.values().map(|replies| replies.len()).sum::<usize>();
+        for replies in self.waiting.values_mut() {
+            replies.retain(|reply| reply.timestamp() + MAX_NESTING >= time);
+        }
+        self.waiting.retain(|_, replies| !replies.is_empty());
+        let num_after = self.waiting.values().map(|replies| replies.len()).sum::<usize>();
+
+        self.num_dropped += num_before - num_after;
+    }
+
+    /// counters of the run, reported once the input is exhausted
+    fn report(&self) {
+        println!(
+            "{} {}",
+            format!("[W{}]", self.worker_id).bold().yellow(),
+            format!(
+                "comment index: {} replies and likes to unknown comments dropped",
+                self.num_dropped
+            )
+            .bold()
+            .yellow()
+        );
+    }
+}
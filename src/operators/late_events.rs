@@ -0,0 +1,104 @@
+use timely::dataflow::channels::pact::Pipeline;
+use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
+use timely::dataflow::{Scope, Stream};
+
+use colored::*;
+
+use crate::event::{Event, LateEvent};
+use crate::operators::window_notify::ALLOWED_LATENESS_SEC;
+
+/// Separate the events that arrived too late from the others.
+///
+/// Sources stamp events with their capability, which follows the event timestamps
+/// minus the maximum bounded delay. An event whose timestamp is older than the time
+/// it was stamped with violated the bounded-delay assumption: downstream operators
+/// might have already emitted the windows it belongs to, so it would be silently
+/// folded into the wrong window.
+/// Events late by at most ALLOWED_LATENESS_SEC are kept in the stream,
+/// `window_notify` amends the windows they belong to.
+///
+/// The operator emits 2 streams as output:
+///     1) the events that arrived in time
+///     2) the late events, with how late they were (in seconds)
+///
+/// Each worker counts the events and the late ones, and reports them
+/// whenever new late events are found.
+pub trait LateEvents<G: Scope> {
+    fn split_late(&self, worker_id: usize) -> (Stream<G, Event>, Stream<G, LateEvent>);
+}
+
+impl<G: Scope<Timestamp = u64>> LateEvents<G> for Stream<G, Event> {
+    fn split_late(&self, worker_id: usize) -> (Stream<G, Event>, Stream<G, LateEvent>) {
+        let mut builder = OperatorBuilder::new("LateEvents".to_owned(), self.scope());
+
+        let mut input = builder.new_input(self, Pipeline);
+
+        let (mut event_output, event_stream) = builder.new_output();
+        let (mut late_output, late_stream) = builder.new_output();
+
+        builder.build(move |_| {
+            let allowed_lateness = *ALLOWED_LATENESS_SEC;
+            let mut counters = LateCounters::default();
+
+            move |_frontiers| {
+                let mut event_handle = event_output.activate();
+                let mut late_handle = late_output.activate();
+
+                let mut buf = Vec::new();
+                input.for_each(|time, data| {
+                    data.swap(&mut buf);
+
+                    let time = time.retain();
+                    let mut event_session = event_handle.session(&time);
+                    let mut late_session = late_handle.session(&time);
+
+                    let num_late = counters.num_late;
+                    for event in buf.drain(..) {
+                        counters.num_events += 1;
+                        match time.time().checked_sub(event.timestamp()) {
+                            Some(lateness) if lateness > allowed_lateness => {
+                                counters.num_late += 1;
+                                counters.max_lateness = counters.max_lateness.max(lateness);
+                                late_session
+                                    .give(LateEvent { event: event, lateness: lateness });
+                            }
+                            _ => event_session.give(event),
+                        }
+                    }
+
+                    if counters.num_late > num_late {
+                        counters.dump(worker_id);
+                    }
+                });
+            }
+        });
+
+        (event_stream, late_stream)
+    }
+}
+
+/// how often the bounded-delay assumption has been violated so far
+#[derive(Default)]
+struct LateCounters {
+    num_events:   u64,
+    num_late:     u64,
+    max_lateness: u64,
+}
+
+impl LateCounters {
+    fn dump(&self, worker_id: usize) {
+        println!(
+            "{} {}",
+            format!("[W{}]", worker_id).bold().magenta(),
+            format!(
+                "{} late events out of {} so far ({:.2}%), max lateness {}s",
+                self.num_late,
+                self.num_events,
+                100. * self.num_late as f64 / self.num_events as f64,
+                self.max_lateness
+            )
+            .bold()
+            .magenta()
+        );
+    }
+}
@@ -1,6 +1,10 @@
 pub mod active_posts;
+pub mod comment_index;
 pub mod friend_recommendations;
+pub mod late_events;
 pub mod post_freq;
 pub mod post_trees;
+pub mod trending;
 pub mod unique_words;
 pub mod window_notify;
+pub mod windows;
@@ -1,3 +1,4 @@
+use crate::checkpoint::{Checkpointer, Stash};
 use crate::event::Event;
 use crate::percentile::Percentile;
 
@@ -35,35 +36,49 @@ use timely::dataflow::{Scope, Stream};
 ///  overlaps the last BURST_WINDOW seconds. Buckets are deleted when they become outdated.
 ///
 pub trait PostFrequency<G: Scope> {
-    fn post_frequency(&self, worker_id: usize) -> Stream<G, u64>;
+    fn post_frequency(&self, worker_id: usize, checkpointer: &Checkpointer) -> Stream<G, u64>;
 }
 
 impl<G: Scope<Timestamp = u64>> PostFrequency<G> for Stream<G, Event> {
-    fn post_frequency(&self, worker_id: usize) -> Stream<G, u64> {
-        let mut state = PostFrequencyState::new(worker_id);
+    fn post_frequency(&self, worker_id: usize, checkpointer: &Checkpointer) -> Stream<G, u64> {
+        let mut checkpoint = checkpointer.register("PostFrequency");
+        let mut state = checkpoint.restore().unwrap_or_else(|| PostFrequencyState::new(worker_id));
+
+        self.unary_frontier(Pipeline, "PostFrequency", move |_, _| {
+            // events of the next epoch, waiting for the checkpoint of the current one
+            let mut stash = Stash::new();
 
-        self.unary(Pipeline, "PostFrequency", move |_, _| {
             move |input, output| {
-                let mut buf = Vec::new();
+                let mut process =
+                    |state: &mut PostFrequencyState, time: Capability<u64>, events: Vec<Event>| {
+                        for event in events {
+                            state.update(&event, *time.time());
+                        }
+
+                        let mut session = output.session(&time);
+                        for id in state.new_spam_person_ids.drain(..) {
+                            session.give(id);
+                        }
+                    };
 
                 input.for_each(|time, data| {
+                    let mut buf = Vec::new();
                     data.swap(&mut buf);
 
-                    for event in buf.drain(..) {
-                        state.update(&event, *time.time());
-                    }
-
-                    let mut session = output.session(&time);
-                    for id in state.new_spam_person_ids.drain(..) {
-                        session.give(id);
+                    if checkpoint.is_ahead(*time.time()) {
+                        stash.push(time.retain(), buf);
+                    } else {
+                        process(&mut state, time.retain(), buf);
                     }
                 });
+
+                checkpoint.align(input.frontier().frontier(), &mut stash, &mut state, &mut process);
             }
         })
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct PostFrequencyState {
     worker_id: usize,
 
@@ -72,6 +87,7 @@ struct PostFrequencyState {
     person_to_event_count: HashMap<u64, u64>,
 
     percentile:          Percentile,
+    #[serde(skip)]
     new_spam_person_ids: Vec<u64>,
     all_spam_person_ids: HashSet<u64>,
 }
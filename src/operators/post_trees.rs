@@ -1,138 +1,319 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 
 use timely::dataflow::channels::pact::Pipeline;
 use timely::dataflow::operators::generic::builder_rc::OperatorBuilder;
+use timely::dataflow::operators::{Branch, Capability, Concat, ConnectLoop, Exchange, Feedback, Map};
 use timely::dataflow::{Scope, Stream};
 
 use colored::*;
 
+use crate::checkpoint::{Checkpointer, Stash};
 use crate::event::{Event, ID};
+use crate::spill::SpillStore;
+
+use crate::operators::comment_index::{CommentIndex, IndexMessage};
 
 use crate::operators::active_posts::StatUpdate;
 use crate::operators::active_posts::StatUpdateType;
 use crate::operators::friend_recommendations::RecommendationUpdate;
 
+pub const EVICTION_FREQ: u64 = 30 * 60; // look for inactive trees every 30 minutes
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    pub static ref INACTIVE_TREE_SEC: u64 =
+        SETTINGS.get::<u64>("POST_TREES_INACTIVE_HOURS").unwrap() * 3600;
+    pub static ref SPILL_DIR: String = SETTINGS.get::<String>("POST_TREES_SPILL_DIR").unwrap();
+    static ref DUPLICATE_POLICY: DuplicatePolicy = {
+        match SETTINGS.get::<String>("POST_TREES_DUPLICATES").unwrap().as_ref() {
+            "drop" => DuplicatePolicy::Drop,
+            "keep-first" => DuplicatePolicy::KeepFirst,
+            "keep-latest" => DuplicatePolicy::KeepLatest,
+            "report" => DuplicatePolicy::Report,
+            other => panic!("unknown POST_TREES_DUPLICATES {:?}", other),
+        }
+    };
+}
+
+/// partition events by the post_id they refer to
+/// in case of replies (and likes to comments), we don't know the root post_id at this stage
+/// => look it up in the distributed index of the comments (see the `comment_index`
+/// module), and send them only to the worker owning their post tree
+pub fn route_replies<G>(
+    events: &Stream<G, Event>,
+    worker_id: usize,
+    checkpointer: &Checkpointer,
+) -> Stream<G, Event>
+where
+    G: Scope<Timestamp = u64>,
+{
+    let (single, replies) = events.branch(|_, event| match event {
+        Event::Comment(c) => c.reply_to_comment_id != None,
+        Event::Like(l) => l.comment_id != None,
+        _ => false,
+    });
+
+    // comments to a post are the first entries of the index
+    let entries = single.flat_map(|event| match event {
+        Event::Comment(c) => c.reply_to_post_id_u64.map(|post_id| IndexMessage::Entry {
+            comment_id:   c.comment_id_u64,
+            root_post_id: post_id,
+        }),
+        _ => None,
+    });
+
+    // the entries of the resolved replies are fed back to the index
+    let (handle, reply_entries) = events.scope().feedback(1);
+    let (resolved, new_entries) = entries
+        .concat(&replies.map(IndexMessage::Reply))
+        .concat(&reply_entries)
+        .comment_index(worker_id, checkpointer);
+    new_entries.connect_loop(handle);
+
+    let single = single.exchange(|event| event.target_id());
+    let resolved = resolved.exchange(|(_, root_post_id)| *root_post_id).map(|(event, _)| event);
+
+    single.concat(&resolved)
+}
+
 /// Given a stream of events, group them in connected components
 /// based on the root post id that they refer to.
 /// In other words, build the tree of events for each post.
 ///
-/// The operator emits 2 streams as output:
+/// The operator emits 3 streams as output:
 ///     1) StatUpdates: will be fed into the `active_posts` operator
 ///                     that implements query 1
 ///     2) RecommendationUpdates: will be fed into the `friend_recommendation`
 ///                     operator that implements query 2
+///     3) DuplicateEvents: diagnostic stream of the events with an id
+///                     already in a post tree
 ///
 /// In case of multiple workers, an upstream `exchange` operator
 /// will partition the events by root post id. Thus this operator
 /// will handle only a subset of the posts.
 ///
-/// "Reply to comments" events are broadcasted to all workers
-/// as they don't carry the root post id in the payload.
+/// "Reply to comments" and "like to comments" events don't carry the root post id
+/// in the payload, they are routed to the right worker by `route_replies`.
+/// Likes count for the root post of the liked comment, and relate
+/// the person who liked it to the author of the comment.
+///
+/// When the `post_trees` operator receives a Reply (or a Like) event that
+/// cannot match to any currently received comment (or post), it stores
+/// it in an out-of-order (ooo) queue, indexed by the timestamp of the events.
+/// When the maximum bounded delay has expired, old events in the ooo queue
+/// are discarded one by one. The events resolved from the ooo queue and the ones
+/// discarded are counted, and reported once the input is exhausted.
+///
+/// If POST_TREES_INACTIVE_HOURS is set (e.g. to the 12 hours of the active posts
+/// window), trees without activity for that long are evicted from memory.
+/// If POST_TREES_SPILL_DIR is set, their nodes are spilled to disk, and looked up
+/// there by the events that refer to an unknown post or comment (e.g. late replies
+/// to an evicted tree), otherwise such events end up dropped from the ooo queue.
 ///
-/// When the `post_trees` operator receives an Reply event that
-/// cannot match to any currently received comment, it stores
-/// it in an out-of-order (ooo) queue. When the maximum bounded delay
-/// has expired, old events in the ooo queue are discarded
-/// (including events do not belong to the posts handled by this worker)
+/// A post or comment whose id is already in a post tree is a duplicate (usually
+/// a bug of the producer), it is counted and reported on the diagnostic stream,
+/// its updates are never emitted. The post tree is handled according to POST_TREES_DUPLICATES:
+///     - "drop", "keep-first" and "report": the duplicate is discarded, the first
+///                     event stays in the post tree
+///     - "keep-latest": replaces the first event in the post tree (later events
+///                     refer to it)
+/// Only the trees in memory are checked, duplicates of evicted trees go unnoticed.
+///
+/// If checkpointing is enabled, the post trees and the ooo queue are saved
+/// at the end of every epoch (see the `checkpoint` module).
 ///
 pub trait PostTrees<G: Scope> {
     fn post_trees(
         &self,
         worker_id: usize,
-    ) -> (Stream<G, StatUpdate>, Stream<G, RecommendationUpdate>);
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, StatUpdate>, Stream<G, RecommendationUpdate>, Stream<G, DuplicateEvent>);
 }
 
 impl<G: Scope<Timestamp = u64>> PostTrees<G> for Stream<G, Event> {
     fn post_trees(
         &self,
         worker_id: usize,
-    ) -> (Stream<G, StatUpdate>, Stream<G, RecommendationUpdate>) {
-        let mut state: PostTreesState = PostTreesState::new(worker_id);
+        checkpointer: &Checkpointer,
+    ) -> (Stream<G, StatUpdate>, Stream<G, RecommendationUpdate>, Stream<G, DuplicateEvent>) {
+        let mut checkpoint = checkpointer.register("PostTrees");
+        let mut state = match checkpoint.restore::<PostTreesState>() {
+            Some(mut state) => {
+                state.init_ooo_events();
+                state.spill = spill_store(worker_id, true);
+                state
+            }
+            None => PostTreesState::new(worker_id),
+        };
 
         let mut builder = OperatorBuilder::new("PostTrees".to_owned(), self.scope());
 
         let mut input = builder.new_input(self, Pipeline);
 
-        // declare two output streams, one for each downstream operator
+        // declare two output streams, one for each downstream operator,
+        // and the diagnostic stream of the duplicates
         let (mut stat_output, stat_stream) = builder.new_output();
         let (mut rec_output, rec_stream) = builder.new_output();
+        let (mut dup_output, dup_stream) = builder.new_output();
 
         builder.build(move |_| {
-            let mut buf = Vec::new();
-            move |_frontiers| {
-                input.for_each(|time, data| {
-                    data.swap(&mut buf);
-
-                    for event in buf.drain(..) {
-                        // update the post trees
-                        let (opt_target_id, opt_root_post_id) = state.update_post_tree(&event);
-
-                        // check if the root post_id has been already received
-                        match opt_root_post_id {
-                            Some(root_post_id) => {
-                                if let ID::Post(pid) = root_post_id {
-                                    state.append_output_updates(&event, pid);
-                                } else {
-                                    panic!("expect ID::Post, got ID::Comment");
-                                }
-
-                                // check whether we can pop some stuff out of the ooo map
-                                if let Some(_) = event.id() {
-                                    state.process_ooo_events(&event);
-                                }
-                            }
-                            None => {
-                                state.push_ooo_event(event, opt_target_id.unwrap());
-                            }
-                        };
-
-                        // state.dump();
-                    }
-
-                    let mut stat_handle = stat_output.activate();
-                    let mut rec_handle = rec_output.activate();
+            // events of the next epoch, waiting for the checkpoint of the current one
+            let mut stash = Stash::new();
+            let mut reported = false;
+
+            move |frontiers| {
+                let mut stat_handle = stat_output.activate();
+                let mut rec_handle = rec_output.activate();
+                let mut dup_handle = dup_output.activate();
+
+                // update the post trees and emit the resulting updates
+                let mut process =
+                    |state: &mut PostTreesState, time: Capability<u64>, events: Vec<Event>| {
+                        state.process_events(events, *time.time());
+
+                        let mut stat_session = stat_handle.session(&time);
+                        let mut rec_session = rec_handle.session(&time);
+
+                        // emit stat updates as output
+                        for stat_update in state.pending_stat_updates.drain(..) {
+                            stat_session.give(stat_update);
+                        }
+
+                        // emit recommendation updates as output
+                        for rec_update in state.pending_rec_updates.drain(..) {
+                            rec_session.give(rec_update);
+                        }
+
+                        // emit duplicates on the diagnostic stream
+                        let mut dup_session = dup_handle.session(&time);
+                        for duplicate in state.pending_duplicates.drain(..) {
+                            dup_session.give(duplicate);
+                        }
+                    };
 
-                    let mut stat_session = stat_handle.session(&time);
-                    let mut rec_session = rec_handle.session(&time);
+                input.for_each(|time, data| {
+                    let mut events = Vec::new();
+                    data.swap(&mut events);
 
-                    // emit stat updates as output
-                    for stat_update in state.pending_stat_updates.drain(..) {
-                        stat_session.give(stat_update);
+                    if checkpoint.is_ahead(*time.time()) {
+                        stash.push(time.retain(), events);
+                    } else {
+                        process(&mut state, time.retain(), events);
                     }
+                });
 
-                    // emit recommendation updates as output
-                    for rec_update in state.pending_rec_updates.drain(..) {
-                        rec_session.give(rec_update);
-                    }
+                checkpoint.align(frontiers[0].frontier(), &mut stash, &mut state, &mut process);
 
-                    // check we if we can clean some old events from the ooo queue
-                    state.clean_ooo_events(*time.time());
-                });
+                // the input is exhausted (e.g. end of the stream), report the counters once
+                if frontiers[0].frontier().is_empty() && !reported {
+                    state.report();
+                    reported = true;
+                }
             }
         });
 
-        // return the two output streams
-        (stat_stream, rec_stream)
+        // return the output streams
+        (stat_stream, rec_stream, dup_stream)
     }
 }
 
-#[derive(Debug)]
+/// how to handle an event whose id is already in a post tree
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    Drop,
+    KeepFirst,
+    KeepLatest,
+    Report,
+}
+
+/// A post or comment whose id was already in a post tree, routed to the diagnostic stream
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateEvent {
+    pub event:              Event,
+    // creator and root post of the event first received with this id
+    pub first_person_id:    u64,
+    pub first_root_post_id: u64,
+    pub policy:             DuplicatePolicy,
+}
+
+impl abomonation::Abomonation for DuplicateEvent {}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct Node {
     person_id:    u64, // "creator" of the event
     root_post_id: ID,
 }
 
+/// nodes of a post tree, to evict them once the tree is inactive
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tree {
+    last_activity: u64,
+    nodes:         Vec<ID>,
+}
+
+/// store of the evicted trees of the worker, if spilling is enabled:
+/// the trees spilled by a previous run are only kept when its state is restored
+fn spill_store(worker_id: usize, restored: bool) -> Option<SpillStore<ID, Node>> {
+    if SPILL_DIR.is_empty() {
+        return None;
+    }
+    let dir = PathBuf::from(&*SPILL_DIR).join(format!("w{}", worker_id));
+    if restored {
+        Some(SpillStore::open(dir))
+    } else {
+        Some(SpillStore::new(dir))
+    }
+}
+
 /// State associated with the `post_trees` operator
+#[derive(Serialize, Deserialize)]
 struct PostTreesState {
     worker_id: usize,
     // event ID --> post ID it refers to (root of the tree)
     root_of: HashMap<ID, Node>,
+    // root post ID --> nodes of the tree in `root_of`
+    #[serde(default)]
+    trees: HashMap<u64, Tree>,
+    // time of the next look for inactive trees
+    #[serde(default)]
+    next_eviction: u64,
+    // evicted trees, looked up by the events that do not match any tree
+    #[serde(skip)]
+    spill: Option<SpillStore<ID, Node>>,
+    // trees evicted and nodes restored from the spill store so far
+    #[serde(skip)]
+    num_evicted: usize,
+    #[serde(skip)]
+    num_restored: usize,
     // out-of-order events: id of missing event --> event that depends on it
     ooo_events: HashMap<ID, Vec<Event>>,
+    // timestamp --> id of missing events with out-of-order events at that timestamp,
+    // rebuilt from `ooo_events` after a restore
+    #[serde(skip)]
+    ooo_expiry: BTreeMap<u64, Vec<ID>>,
+    // out-of-order events resolved so far, once their target arrived
+    #[serde(skip)]
+    num_resolved_ooo: usize,
+    // out-of-order events dropped so far because their target never arrived in time
+    #[serde(skip)]
+    num_dropped_ooo: usize,
     // updates to be sent on the stat output stream
+    #[serde(skip)]
     pending_stat_updates: Vec<StatUpdate>,
     // updates to be sent on the recommendation output stream
+    #[serde(skip)]
     pending_rec_updates: Vec<RecommendationUpdate>,
+    // duplicates found so far, and the ones to be sent on the diagnostic stream
+    #[serde(skip)]
+    num_duplicates: usize,
+    #[serde(skip)]
+    pending_duplicates: Vec<DuplicateEvent>,
 }
 
 impl PostTreesState {
@@ -140,64 +321,224 @@ impl PostTreesState {
         PostTreesState {
             worker_id:            worker_id,
             root_of:              HashMap::<ID, Node>::new(),
+            trees:                HashMap::new(),
+            next_eviction:        0,
+            spill:                spill_store(worker_id, false),
+            num_evicted:          0,
+            num_restored:         0,
             ooo_events:           HashMap::<ID, Vec<Event>>::new(),
+            ooo_expiry:           BTreeMap::new(),
+            num_resolved_ooo:     0,
+            num_dropped_ooo:      0,
             pending_stat_updates: Vec::new(),
             pending_rec_updates:  Vec::new(),
+            num_duplicates:       0,
+            pending_duplicates:   Vec::new(),
+        }
+    }
+
+    /// process a batch of events received at `time`,
+    /// the resulting updates are appended to the pending lists
+    fn process_events(&mut self, events: Vec<Event>, time: u64) {
+        for event in events {
+            // update the post trees
+            let (opt_target_id, opt_root_post_id, is_new) = self.update_post_tree(&event);
+
+            // check if the root post_id has been already received
+            match opt_root_post_id {
+                Some(root_post_id) => {
+                    if let ID::Post(pid) = root_post_id {
+                        if is_new {
+                            self.append_output_updates(&event, pid);
+                        }
+                    } else {
+                        panic!("expect ID::Post, got ID::Comment");
+                    }
+
+                    // check whether we can pop some stuff out of the ooo map
+                    if let Some(_) = event.id() {
+                        self.process_ooo_events(&event);
+                    }
+                }
+                None => {
+                    self.push_ooo_event(event, opt_target_id.unwrap());
+                }
+            };
+
+            // self.dump();
+        }
+
+        // check we if we can clean some old events from the ooo queue
+        self.clean_ooo_events(time);
+
+        // forget the trees that became inactive
+        self.evict_trees(time);
+    }
+
+    /// ids of the events are not part of the checkpoints, fill them in after a restore,
+    /// and rebuild the expiry index
+    fn init_ooo_events(&mut self) {
+        for (target_id, events) in self.ooo_events.iter_mut() {
+            *events = events.drain(..).map(|event| event.init()).collect();
+            for event in events.iter() {
+                self.ooo_expiry.entry(event.timestamp()).or_insert(Vec::new()).push(*target_id);
+            }
         }
     }
 
-    /// given an event, try to match it to some post tree
-    fn update_post_tree(&mut self, event: &Event) -> (Option<ID>, Option<ID>) {
+    /// given an event, try to match it to some post tree,
+    /// returns the id it refers to, its root post and whether its updates have to be emitted
+    fn update_post_tree(&mut self, event: &Event) -> (Option<ID>, Option<ID>, bool) {
         match event {
             Event::Post(post) => {
                 let node = Node { person_id: post.person_id, root_post_id: post.post_id };
-                self.root_of.insert(post.post_id, node);
-                (None, Some(post.post_id))
+                let is_new = self.insert_event_node(post.post_id, node, event);
+                (None, Some(post.post_id), is_new)
             }
             Event::Like(like) => {
-                // likes are not stored in the tree
-                let post_id = match self.root_of.get(&like.post_id) {
-                    Some(_) => Some(like.post_id), // can only like a post
-                    None => None,
-                };
-                (Some(like.post_id), post_id)
+                // likes are not stored in the tree, they can like a post or a comment
+                let liked_id = like.liked_id();
+                (Some(liked_id), self.lookup(&liked_id, event.timestamp()), true)
             }
             Event::Comment(comment) => {
                 let reply_to_id = comment.reply_to_post_id.or(comment.reply_to_comment_id).unwrap();
 
-                if let Some(root_node) = self.root_of.get(&reply_to_id) {
-                    let root_post_id = root_node.root_post_id;
+                if let Some(root_post_id) = self.lookup(&reply_to_id, event.timestamp()) {
                     let node = Node { person_id: comment.person_id, root_post_id: root_post_id };
-                    self.root_of.insert(comment.comment_id, node);
-                    (Some(reply_to_id), Some(root_post_id))
+                    let is_new = self.insert_event_node(comment.comment_id, node, event);
+                    (Some(reply_to_id), Some(root_post_id), is_new)
                 } else {
-                    (Some(reply_to_id), None)
+                    (Some(reply_to_id), None, true)
                 }
             }
         }
     }
 
+    /// add the node of an event to its post tree, unless its id is already there:
+    /// the duplicate is then handled according to DUPLICATE_POLICY.
+    /// Returns whether the updates of the event have to be emitted
+    fn insert_event_node(&mut self, id: ID, node: Node, event: &Event) -> bool {
+        let first = self.root_of.get(&id).map(|first| (first.person_id, first.root_post_id));
+        let (first_person_id, first_root_post_id) = match first {
+            Some(first) => first,
+            None => {
+                self.insert_node(id, node, event.timestamp());
+                return true;
+            }
+        };
+
+        let policy = *DUPLICATE_POLICY;
+        self.num_duplicates += 1;
+        self.pending_duplicates.push(DuplicateEvent {
+            event:              event.clone(),
+            first_person_id:    first_person_id,
+            first_root_post_id: first_root_post_id.u64(),
+            policy:             policy,
+        });
+
+        // the updates of a duplicate are never emitted, the stats are left unchanged
+        if policy == DuplicatePolicy::KeepLatest {
+            self.insert_node(id, node, event.timestamp());
+        }
+        false
+    }
+
+    /// add a node to its post tree
+    fn insert_node(&mut self, id: ID, node: Node, timestamp: u64) {
+        let tree = self.trees.entry(node.root_post_id.u64()).or_insert(Tree::default());
+        tree.last_activity = tree.last_activity.max(timestamp);
+        tree.nodes.push(id);
+        self.root_of.insert(id, node);
+    }
+
+    /// root post of the tree the id belongs to, looking into the evicted trees
+    /// if it is not in memory (the node is then restored)
+    fn lookup(&mut self, id: &ID, timestamp: u64) -> Option<ID> {
+        if let Some(node) = self.root_of.get(id) {
+            return Some(node.root_post_id);
+        }
+
+        let spill = self.spill.as_ref()?;
+        let node = spill.lookup(id)?;
+        let root_post_id = node.root_post_id;
+        // the root post is needed as well, to know the author of the post
+        let root_node = if *id == root_post_id || self.root_of.contains_key(&root_post_id) {
+            None
+        } else {
+            Some(spill.lookup(&root_post_id)?)
+        };
+
+        self.num_restored += 1;
+        if let Some(root_node) = root_node {
+            self.insert_node(root_post_id, root_node, timestamp);
+        }
+        self.insert_node(*id, node, timestamp);
+        Some(root_post_id)
+    }
+
+    /// every EVICTION_FREQ, remove from memory the trees without activity
+    /// in the last INACTIVE_TREE_SEC (and spill them to disk, if enabled)
+    fn evict_trees(&mut self, timestamp: u64) {
+        // eviction is disabled
+        if *INACTIVE_TREE_SEC == 0 || timestamp < self.next_eviction {
+            return;
+        }
+        self.next_eviction = timestamp + EVICTION_FREQ;
+
+        let inactive = self
+            .trees
+            .iter()
+            .filter(|(_, tree)| tree.last_activity + *INACTIVE_TREE_SEC <= timestamp)
+            .map(|(&root_post_id, _)| root_post_id)
+            .collect::<Vec<_>>();
+        if inactive.is_empty() {
+            return;
+        }
+
+        let mut evicted = Vec::new();
+        for root_post_id in inactive.iter() {
+            let tree = self.trees.remove(root_post_id).unwrap();
+            for id in tree.nodes {
+                // the id may have moved to another tree since (see DUPLICATE_POLICY)
+                let root = self.root_of.get(&id).map(|node| node.root_post_id.u64());
+                if root == Some(*root_post_id) {
+                    evicted.push((id, self.root_of.remove(&id).unwrap()));
+                }
+            }
+        }
+        if let Some(spill) = self.spill.as_mut() {
+            spill.write(evicted);
+        }
+
+        self.num_evicted += inactive.len();
+    }
+
     /// process events that have `root_event` as their target post,
     /// recursively process the newly inserted events
     fn process_ooo_events(&mut self, root_event: &Event) {
         let id = root_event.id().unwrap();
         if let Some(events) = self.ooo_events.remove(&id) {
-            println!("-- {} for id = {:?}", "process_ooo_events".bold().yellow(), id);
-
             let mut new_events = Vec::new();
             for event in events {
-                let (opt_target_id, opt_root_post_id) = self.update_post_tree(&event);
+                let (opt_target_id, opt_root_post_id, is_new) = self.update_post_tree(&event);
                 assert!(opt_target_id.unwrap() == id, "wtf");
                 let root_post_id =
                     opt_root_post_id.expect("[process_ooo_events] root_post_id is None");
+                if !is_new {
+                    // a duplicate, already counted
+                    continue;
+                }
 
                 // only use this event if its timestamp is greater or equal to the parent's.
                 if event.timestamp() >= root_event.timestamp() {
                     self.append_output_updates(&event, root_post_id.u64());
+                    self.num_resolved_ooo += 1;
 
                     if let Some(_) = event.id() {
                         new_events.push(event);
                     }
+                } else {
+                    self.num_dropped_ooo += 1;
                 }
             }
 
@@ -210,22 +551,51 @@ impl PostTreesState {
 
     /// insert an event into the out-of-order queue
     fn push_ooo_event(&mut self, event: Event, target_id: ID) {
+        self.ooo_expiry.entry(event.timestamp()).or_insert(Vec::new()).push(target_id);
         self.ooo_events.entry(target_id).or_insert(Vec::new()).push(event);
     }
 
-    /// remove all old events from the out-of-order queue
-    /// (including Reply events there were not meant to be received by this worker)
+    /// remove the events of the out-of-order queue not newer than `timestamp`,
+    /// only visiting the targets of the expired events
     fn clean_ooo_events(&mut self, timestamp: u64) {
-        self.ooo_events = self
-            .ooo_events
-            .clone()
-            .into_iter()
-            .filter(|(_, events)| events.iter().all(|event| event.timestamp() > timestamp))
-            .collect::<HashMap<_, _>>();
+        let remaining = self.ooo_expiry.split_off(&(timestamp + 1));
+        let expired = std::mem::replace(&mut self.ooo_expiry, remaining);
+
+        for target_id in expired.into_iter().flat_map(|(_, target_ids)| target_ids) {
+            // the target may have arrived in the meantime (or already been cleaned)
+            if let Some(events) = self.ooo_events.get_mut(&target_id) {
+                let num_before = events.len();
+                events.retain(|event| event.timestamp() > timestamp);
+                self.num_dropped_ooo += num_before - events.len();
+                if events.is_empty() {
+                    self.ooo_events.remove(&target_id);
+                }
+            }
+        }
+    }
+
+    /// counters of the run, reported once the input is exhausted
+    fn report(&self) {
+        println!(
+            "{} {}",
+            format!("[W{}]", self.worker_id).bold().yellow(),
+            format!(
+                "post trees: {} out-of-order events resolved late, {} dropped, \
+                 {} inactive trees evicted, {} nodes restored from the evicted trees",
+                self.num_resolved_ooo, self.num_dropped_ooo, self.num_evicted, self.num_restored
+            )
+            .bold()
+            .yellow()
+        );
     }
 
     /// generate all output updates for the current event
     fn append_output_updates(&mut self, event: &Event, root_post_id: u64) {
+        // any event keeps the tree active (e.g. likes)
+        if let Some(tree) = self.trees.get_mut(&root_post_id) {
+            tree.last_activity = tree.last_activity.max(event.timestamp());
+        }
+
         self.append_stat_update(&event, root_post_id);
         self.append_rec_update(&event, root_post_id);
     }
@@ -275,8 +645,9 @@ impl PostTreesState {
                 to_person_id:   to_person_id,
             };
             self.pending_rec_updates.push(update)
-        } else if let Event::Like(_) = event {
-            let to_person_id = self.root_of.get(&ID::Post(root_post_id)).unwrap().person_id;
+        } else if let Event::Like(like) = event {
+            // the author of the liked post or comment
+            let to_person_id = self.root_of.get(&like.liked_id()).unwrap().person_id;
             let update = RecommendationUpdate::Like {
                 timestamp:      event.timestamp(),
                 from_person_id: event.person_id(),
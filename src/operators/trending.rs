@@ -0,0 +1,181 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use timely::dataflow::channels::pact::{Exchange, Pipeline};
+use timely::dataflow::operators::Operator;
+use timely::dataflow::{Scope, Stream};
+
+use crate::operators::active_posts::{PostStatus, Stats, ACTIVE_WINDOW_SECONDS, DIFF_OUTPUT};
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    static ref TOP_K: usize = SETTINGS.get::<usize>("TRENDING_TOP_K").unwrap();
+    static ref FORMULA: TrendingFormula = TrendingFormula {
+        comment_weight: SETTINGS.get::<f64>("TRENDING_COMMENT_WEIGHT").unwrap(),
+        reply_weight:   SETTINGS.get::<f64>("TRENDING_REPLY_WEIGHT").unwrap(),
+        people_weight:  SETTINGS.get::<f64>("TRENDING_PEOPLE_WEIGHT").unwrap(),
+        like_weight:    SETTINGS.get::<f64>("TRENDING_LIKE_WEIGHT").unwrap(),
+        half_life_sec:  SETTINGS.get::<f64>("TRENDING_HALF_LIFE_HOURS").unwrap() * 3600.0,
+    };
+}
+
+/// Given the statistics of the active posts emitted by every worker,
+/// emit the TRENDING_TOP_K hottest posts of the whole cluster
+/// at every notification, ordered by decreasing score.
+///
+/// The score of a post is a linear combination of its comments, replies,
+/// people and likes in the last 12 hours, halved every TRENDING_HALF_LIFE_HOURS
+/// since its latest activity (weights are set in Settings.toml).
+///
+/// Posts are partitioned among the workers, so each worker only needs to
+/// send its local top k to worker 0, which merges them into the global ranking.
+/// Each worker keeps the latest stats of its active posts, so that the
+/// ranking is complete with ACTIVE_POSTS_OUTPUT = "diff" as well.
+/// The windows of the workers do not end at the same times, so worker 0
+/// merges the latest local ranking of every worker, scored at the time of
+/// the notification. Amendments of the active posts are not taken into account.
+///
+pub trait Trending<G: Scope> {
+    fn trending_posts(&self, worker_id: usize) -> Stream<G, Vec<TrendingPost>>;
+}
+
+impl<G: Scope<Timestamp = u64>> Trending<G> for Stream<G, HashMap<u64, PostStatus>> {
+    fn trending_posts(&self, worker_id: usize) -> Stream<G, Vec<TrendingPost>> {
+        let top_k = *TOP_K;
+
+        // post ID --> latest engagement of the active posts of the worker
+        let mut active = HashMap::<u64, Candidate>::new();
+
+        // score the posts of the worker and keep its top k
+        let local = self.unary(Pipeline, "TrendingScores", move |_, _| {
+            let mut buf = Vec::new();
+            move |input, output| {
+                input.for_each(|time, data| {
+                    data.swap(&mut buf);
+                    let mut session = output.session(&time);
+                    for stats in buf.drain(..) {
+                        // the full output only carries the posts that are still active
+                        if !*DIFF_OUTPUT {
+                            active.clear();
+                        }
+                        for (&post_id, status) in stats.iter() {
+                            if let PostStatus::Active(stats) = status {
+                                active.insert(post_id, FORMULA.candidate(post_id, stats));
+                            } else {
+                                active.remove(&post_id);
+                            }
+                        }
+                        let candidates = active.values().cloned().collect();
+                        session.give((worker_id, top(candidates, *time.time(), top_k)));
+                    }
+                });
+            }
+        });
+
+        // time --> local rankings received so far, with the worker that sent them
+        let mut pending = HashMap::<u64, Vec<(usize, Vec<Candidate>)>>::new();
+        // worker --> its latest local ranking
+        let mut latest = HashMap::<usize, Vec<Candidate>>::new();
+        let mut buf = Vec::new();
+
+        // merge the latest local rankings into the global one
+        local.unary_notify(
+            Exchange::new(|_| 0),
+            "TrendingPosts",
+            None,
+            move |input, output, notificator| {
+                input.for_each(|time, data| {
+                    data.swap(&mut buf);
+                    pending.entry(*time.time()).or_insert_with(Vec::new).extend(buf.drain(..));
+                    notificator.notify_at(time.retain());
+                });
+
+                notificator.for_each(|time, _, _| {
+                    if let Some(rankings) = pending.remove(time.time()) {
+                        latest.extend(rankings);
+                        let candidates = latest.values().flatten().cloned().collect();
+                        let posts = top(candidates, *time.time(), top_k)
+                            .iter()
+                            .enumerate()
+                            .map(|(i, post)| TrendingPost {
+                                rank:    i + 1,
+                                post_id: post.post_id,
+                                score:   FORMULA.score(post, *time.time()),
+                            })
+                            .collect::<Vec<_>>();
+                        output.session(&time).give(posts);
+                    }
+                });
+            },
+        )
+    }
+}
+
+/// a post of the ranking, `rank` starts from 1
+#[derive(Clone, Debug, Serialize)]
+pub struct TrendingPost {
+    pub rank:    usize,
+    pub post_id: u64,
+    pub score:   f64,
+}
+
+impl abomonation::Abomonation for TrendingPost {}
+
+/// engagement of an active post in the last 12 hours, scored at any time
+#[derive(Clone, Debug)]
+struct Candidate {
+    post_id:       u64,
+    engagement:    f64,
+    last_activity: u64,
+}
+
+impl abomonation::Abomonation for Candidate {}
+
+/// weights of the trending score
+struct TrendingFormula {
+    comment_weight: f64,
+    reply_weight:   f64,
+    people_weight:  f64,
+    like_weight:    f64,
+    half_life_sec:  f64,
+}
+
+impl TrendingFormula {
+    fn candidate(&self, post_id: u64, stats: &Stats) -> Candidate {
+        let engagement = match stats.windows.get(&ACTIVE_WINDOW_SECONDS) {
+            Some(recent) => {
+                self.comment_weight * recent.num_comments as f64
+                    + self.reply_weight * recent.num_replies as f64
+                    + self.people_weight * recent.num_people as f64
+                    + self.like_weight * recent.num_likes as f64
+            }
+            None => 0.0,
+        };
+        Candidate {
+            post_id:       post_id,
+            engagement:    engagement,
+            last_activity: stats.last_activity,
+        }
+    }
+
+    fn score(&self, post: &Candidate, timestamp: u64) -> f64 {
+        // recency decay since the latest activity of the post
+        let age = timestamp.saturating_sub(post.last_activity) as f64;
+        post.engagement * 0.5_f64.powf(age / self.half_life_sec)
+    }
+}
+
+/// sort the posts by decreasing score at `timestamp` (ties broken by post id)
+/// and keep the first `top_k`
+fn top(mut posts: Vec<Candidate>, timestamp: u64, top_k: usize) -> Vec<Candidate> {
+    posts.sort_by(|a, b| {
+        let (a_score, b_score) = (FORMULA.score(a, timestamp), FORMULA.score(b, timestamp));
+        b_score.partial_cmp(&a_score).unwrap_or(Ordering::Equal).then(a.post_id.cmp(&b.post_id))
+    });
+    posts.truncate(top_k);
+    posts
+}
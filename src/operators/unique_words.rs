@@ -1,3 +1,4 @@
+use crate::checkpoint::{Checkpointer, Stash};
 use crate::event::Event;
 use crate::percentile::Percentile;
 use std::collections::HashSet;
@@ -17,39 +18,54 @@ use timely::dataflow::{Scope, Stream};
 /// Thus, the operator cannot mark the user's activity as spam multiple times.
 ///
 pub trait UniqueWords<G: Scope> {
-    fn unique_words(&self, worker_id: usize) -> Stream<G, u64>;
+    fn unique_words(&self, worker_id: usize, checkpointer: &Checkpointer) -> Stream<G, u64>;
 }
 
 impl<G: Scope<Timestamp = u64>> UniqueWords<G> for Stream<G, Event> {
-    fn unique_words(&self, worker_id: usize) -> Stream<G, u64> {
-        let mut state = UniqueWordsState::new(worker_id);
+    fn unique_words(&self, worker_id: usize, checkpointer: &Checkpointer) -> Stream<G, u64> {
+        let mut checkpoint = checkpointer.register("UniqueWords");
+        let mut state = checkpoint.restore().unwrap_or_else(|| UniqueWordsState::new(worker_id));
+
+        self.unary_frontier(Pipeline, "UniqueWords", move |_, _| {
+            // events of the next epoch, waiting for the checkpoint of the current one
+            let mut stash = Stash::new();
 
-        self.unary(Pipeline, "UniqueWords", move |_, _| {
             move |input, output| {
-                let mut buf = Vec::new();
+                let mut process =
+                    |state: &mut UniqueWordsState, time: Capability<u64>, events: Vec<Event>| {
+                        for event in events {
+                            state.update(&event, *time.time());
+                        }
+
+                        let mut session = output.session(&time);
+                        for id in state.new_spam_person_ids.drain(..) {
+                            session.give(id);
+                        }
+                    };
 
                 input.for_each(|time, data| {
+                    let mut buf = Vec::new();
                     data.swap(&mut buf);
 
-                    for event in buf.drain(..) {
-                        state.update(&event, *time.time());
-                    }
-
-                    let mut session = output.session(&time);
-                    for id in state.new_spam_person_ids.drain(..) {
-                        session.give(id);
+                    if checkpoint.is_ahead(*time.time()) {
+                        stash.push(time.retain(), buf);
+                    } else {
+                        process(&mut state, time.retain(), buf);
                     }
                 });
+
+                checkpoint.align(input.frontier().frontier(), &mut stash, &mut state, &mut process);
             }
         })
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 struct UniqueWordsState {
     worder_id: usize,
 
     percentile:          Percentile,
+    #[serde(skip)]
     new_spam_person_ids: Vec<u64>,
     all_spam_person_ids: HashSet<u64>,
 }
@@ -1,12 +1,27 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::rc::Rc;
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
 use timely::dataflow::channels::pact::Pipeline;
-use timely::dataflow::operators::Operator;
+use timely::dataflow::operators::{Branch, Capability, Map, Operator};
 use timely::dataflow::{Scope, Stream};
 use timely::Data;
 
+use crate::checkpoint::{Checkpointer, Stash};
+
+lazy_static! {
+    static ref SETTINGS: config::Config = {
+        let mut s = config::Config::default();
+        s.merge(config::File::with_name("Settings")).unwrap();
+        s
+    };
+    pub static ref ALLOWED_LATENESS_SEC: u64 = SETTINGS.get::<u64>("ALLOWED_LATENESS_SEC").unwrap();
+}
+
 pub trait Timestamp {
     fn timestamp(&self) -> u64;
 }
@@ -17,31 +32,48 @@ pub trait Timestamp {
 /// 1) window_size: size of the window in seconds (e.g. 30*60 for every 30 minutes)
 /// 2) op_name: name of the operator
 /// 3) state:   the initial state of the operator
-/// 4) on_new_input: callback function called when a new event is received;
+/// 4) checkpointer: saves the state of the window at the end of every epoch,
+///                  the state is restored from it if available
+/// 5) on_new_input: callback function called when a new event is received;
 ///                  it should update the `state` (passed as mutable)
-/// 5) on_notify: callback function called when a notification is delivered;
+/// 6) on_notify: callback function called when a notification is delivered;
 ///               given the state, it should emit the output for that window
 ///
-/// This generic operator serves two main purposes:
+/// This generic operator serves three main purposes:
 /// - implements the windowing logic:
 ///     after the first events is received, it sets
 ///     periodic notification every `window_size` starting from
 ///     the timestamp of the first event
 ///
 /// - handle out-of-order events:
-///     the operator internally keeps two copy of the state;
-///     one for the current window `cur_state`, and
-///     one for the next window `next_state`.
-///     When an event is delivered it will update either
-///     both states or only the `next_state` if the event
-///     belongs to the next window (this could happen as
-///     the internal time capability is delayed by the source
-///     operator taking into account the maximum bounded delay)
+///     the operator keeps a single state, including all the events up to the
+///     window to be notified next. The events of the following windows are
+///     buffered, and applied to the state once it has been notified for the
+///     previous window, so that every event is applied exactly once and the
+///     state is never copied (events can be ahead of the next notification as
+///     the internal time capability is delayed by the source operator taking
+///     into account the maximum bounded delay)
+///
+/// - handle late events:
+///     the state of the windows already notified, as it was before their notification,
+///     is kept until the frontier is ALLOWED_LATENESS_SEC past their end (this is the
+///     only case in which the state is copied, once per window: with a lateness of
+///     L seconds about L / window_size copies are kept). An event belonging to one of
+///     them updates its state, and the output of the window is emitted again as an
+///     `Amendment` on the second output stream: the retraction of the previous output
+///     and the updated one, both computed from a copy of the state (so that they are
+///     complete in diff mode as well, where the output depends on the previous one).
+///     Events later than that are only applied to the current state, they are
+///     counted and reported once the input is exhausted.
+///
+/// - flush the last windows:
+///     once the input is exhausted (e.g. at the end of the stream) the windows
+///     of the events received after the last notification are emitted as well
 ///
 pub trait WindowNotify<
     G: Scope<Timestamp = u64>,
-    D: Data + Timestamp + Debug,
-    S: Clone + 'static,
+    D: Data + Timestamp + Debug + Serialize + DeserializeOwned,
+    S: Clone + Serialize + DeserializeOwned + 'static,
     O: Data,
 >
 {
@@ -50,78 +82,253 @@ pub trait WindowNotify<
         window_size: u64,
         op_name: &'static str,
         state: S,
+        checkpointer: &Checkpointer,
         on_new_input: impl Fn(&mut S, &D, u64) + 'static,
         on_notify: impl Fn(&mut S, u64) -> O + 'static,
-    ) -> Stream<G, O>;
+    ) -> (Stream<G, O>, Stream<G, Amendment<O>>);
+}
+
+/// Correction of the output of a window already notified, caused by late events
+#[derive(Clone, Debug, Serialize)]
+pub struct Amendment<O> {
+    // end of the amended window
+    pub window:     u64,
+    pub retraction: O,
+    pub update:     O,
+}
+
+/// windowing state of the operator, saved at the end of every epoch
+#[derive(Serialize, Deserialize)]
+struct WindowState<S, D> {
+    first_notification:     bool,
+    next_notification_time: u64,
+    // state of the operator, including the events up to the next notification
+    current_state:          S,
+    // end of the window --> events of the windows after the next notification
+    pending_events:         BTreeMap<u64, Vec<D>>,
+    // end of the window --> state before its notification, for the windows notified
+    // within the allowed lateness (their output is computed again from a copy)
+    notified_windows:       BTreeMap<u64, S>,
+    // latest event timestamp seen, to flush the last windows at the end of the stream
+    max_timestamp:          u64,
+}
+
+impl<S: Clone, D> WindowState<S, D> {
+    fn new(state: S) -> WindowState<S, D> {
+        WindowState {
+            first_notification:     true,
+            next_notification_time: std::u64::MAX,
+            current_state:          state,
+            pending_events:         BTreeMap::new(),
+            notified_windows:       BTreeMap::new(),
+            max_timestamp:          0,
+        }
+    }
+
+    /// end of the window the timestamp belongs to, windows are `(end - size, end]`
+    fn window_end(&self, timestamp: u64, window_size: u64) -> u64 {
+        let next = self.next_notification_time;
+        if timestamp > next {
+            next + (timestamp - next + window_size - 1) / window_size * window_size
+        } else {
+            next - (next - timestamp) / window_size * window_size
+        }
+    }
+
+    /// notify the window ending at `time`, then move on to the following one
+    /// applying the events buffered for it
+    fn close<O>(
+        &mut self,
+        time: u64,
+        window_size: u64,
+        allowed_lateness: u64,
+        on_new_input: &impl Fn(&mut S, &D, u64),
+        on_notify: &impl Fn(&mut S, u64) -> O,
+    ) -> O {
+        if allowed_lateness > 0 {
+            self.notified_windows.insert(time, self.current_state.clone());
+        }
+        let out = on_notify(&mut self.current_state, time);
+
+        self.next_notification_time = time + window_size;
+        if let Some(events) = self.pending_events.remove(&self.next_notification_time) {
+            for el in events.iter() {
+                on_new_input(&mut self.current_state, el, self.next_notification_time);
+            }
+        }
+        out
+    }
+
+    /// forget the windows that cannot be amended anymore: events are at most
+    /// `allowed_lateness` late, so none can arrive for a window ending before
+    /// the frontier minus the allowed lateness
+    fn prune(&mut self, frontier: &[u64], allowed_lateness: u64) {
+        match frontier.iter().min() {
+            Some(&time) => {
+                self.notified_windows =
+                    self.notified_windows.split_off(&time.saturating_sub(allowed_lateness))
+            }
+            None => self.notified_windows.clear(),
+        }
+    }
 }
 
-impl<G: Scope<Timestamp = u64>, D: Data + Timestamp + Debug, S: Clone + 'static, O: Data>
-    WindowNotify<G, D, S, O> for Stream<G, D>
+impl<
+        G: Scope<Timestamp = u64>,
+        D: Data + Timestamp + Debug + Serialize + DeserializeOwned,
+        S: Clone + Serialize + DeserializeOwned + 'static,
+        O: Data,
+    > WindowNotify<G, D, S, O> for Stream<G, D>
 {
     fn window_notify(
         &self,
         window_size: u64,
         op_name: &'static str,
         state: S,
+        checkpointer: &Checkpointer,
         on_new_input: impl Fn(&mut S, &D, u64) + 'static,
         on_notify: impl Fn(&mut S, u64) -> O + 'static,
-    ) -> Stream<G, O> {
-        let mut first_notification = true;
-        let mut next_notification_time = std::u64::MAX;
-
-        // keep two states, current/next window
-        let mut cur_state = state;
-        let mut next_state = cur_state.clone();
-
-        self.unary_notify(Pipeline, op_name, None, move |input, output, notificator| {
-            input.for_each(|time, data| {
-                let mut buf = Vec::<D>::new();
-                data.swap(&mut buf);
-
-                if first_notification {
-                    next_notification_time =
-                        buf.iter().map(|el| el.timestamp()).min().expect("wtf") + window_size;
-                    first_notification = false;
-                    // Set up the first notification
-                    notificator.notify_at(time.delayed(&next_notification_time));
-                }
+    ) -> (Stream<G, O>, Stream<G, Amendment<O>>) {
+        let mut checkpoint = checkpointer.register(op_name);
+        let mut window =
+            checkpoint.restore::<WindowState<S, D>>().unwrap_or_else(|| WindowState::new(state));
+        let allowed_lateness = *ALLOWED_LATENESS_SEC;
+
+        // a restored window is waiting for its next notification
+        let init =
+            if window.first_notification { None } else { Some(window.next_notification_time) };
+
+        // events of the next epoch, waiting for the checkpoint of the current one
+        let mut stash = Stash::new();
+        // events that belong to a window that cannot be amended anymore
+        let mut num_late = 0;
 
-                for el in buf.drain(..) {
-                    // use caller-provided function to update the next_state
-                    on_new_input(&mut next_state, &el, next_notification_time);
-                    // the event might belong to the next window
-                    // update the current state only if it does not
-                    if el.timestamp() <= next_notification_time {
-                        on_new_input(&mut cur_state, &el, next_notification_time);
+        let output =
+            self.unary_notify(Pipeline, op_name, init, move |input, output, notificator| {
+                let frontier = notificator.frontier(0).to_vec();
+
+                let mut process = |window: &mut WindowState<S, D>,
+                                   time: Capability<u64>,
+                                   mut buf: Vec<D>| {
+                    if window.first_notification {
+                        window.next_notification_time =
+                            buf.iter().map(|el| el.timestamp()).min().expect("wtf") + window_size;
+                        window.first_notification = false;
+                        // Set up the first notification
+                        notificator.notify_at(time.delayed(&window.next_notification_time));
                     }
-                }
-            });
 
-            // Shut up borrow checker
-            let notified_time = None;
-            let ref1 = Rc::new(RefCell::new(notified_time));
-            let ref2 = Rc::clone(&ref1);
+                    for el in buf.drain(..) {
+                        window.max_timestamp = window.max_timestamp.max(el.timestamp());
+                        let end = window.window_end(el.timestamp(), window_size);
+                        let next = window.next_notification_time;
+
+                        // event of a following window, wait for the state to reach it
+                        if end > next {
+                            window.pending_events.entry(end).or_insert_with(Vec::new).push(el);
+                            continue;
+                        }
+
+                        // use caller-provided function to update the state
+                        on_new_input(&mut window.current_state, &el, next);
+
+                        if end == next {
+                            continue;
+                        }
+
+                        // late event: amend the windows already notified
+                        if !window.notified_windows.contains_key(&end) {
+                            num_late += 1;
+                        }
+                        let mut session = output.session(&time);
+                        for (&end, state) in window.notified_windows.range_mut(end..) {
+                            // the output previously emitted for the window, and the new one
+                            let retraction = on_notify(&mut state.clone(), end);
+                            on_new_input(state, &el, end);
+                            let update = on_notify(&mut state.clone(), end);
+                            session.give(Err(Amendment {
+                                window:     end,
+                                retraction: retraction,
+                                update:     update,
+                            }));
+                        }
+                    }
+                };
+
+                input.for_each(|time, data| {
+                    let mut buf = Vec::<D>::new();
+                    data.swap(&mut buf);
+
+                    if checkpoint.is_ahead(*time.time()) {
+                        stash.push(time.retain(), buf);
+                    } else {
+                        process(&mut window, time.retain(), buf);
+                    }
+                });
+
+                checkpoint.align(&frontier, &mut stash, &mut window, &mut process);
+
+                // Shut up borrow checker
+                let notified_time = None;
+                let ref1 = Rc::new(RefCell::new(notified_time));
+                let ref2 = Rc::clone(&ref1);
 
-            notificator.for_each(|time, _, _| {
-                let mut borrow = ref1.borrow_mut();
-                *borrow = Some(time.clone());
+                notificator.for_each(|time, _, _| {
+                    let mut borrow = ref1.borrow_mut();
+                    *borrow = Some(time.clone());
 
-                // use caller-provided function to generate the output
-                let out = on_notify(&mut cur_state, *time.time());
+                    // use caller-provided function to generate the output
+                    let out = window.close(
+                        *time.time(),
+                        window_size,
+                        allowed_lateness,
+                        &on_new_input,
+                        &on_notify,
+                    );
 
-                let mut session = output.session(&time);
-                session.give(out);
+                    let mut session = output.session(&time);
+                    session.give(Ok(out));
+                });
 
-                // we are switching to the next window, flip the state
-                cur_state = next_state.clone();
+                let borrow = ref2.borrow();
+                // the input has been exhausted (e.g. end of the stream), do not
+                // set up any other notification so that the operator can shut down
+                let input_done = notificator.frontier(0).is_empty();
+                match (&*borrow, input_done) {
+                    (Some(cap), false) => {
+                        // setup next notification to the previous notification time + window_size
+                        notificator.notify_at(cap.delayed(&window.next_notification_time));
+                    }
+                    (Some(cap), true) => {
+                        if num_late > 0 {
+                            println!(
+                                "[{}] {} events later than the allowed lateness",
+                                op_name, num_late
+                            );
+                        }
+
+                        // flush the windows of the events received after the last notification
+                        let mut time = *cap.time();
+                        while window.max_timestamp > time {
+                            time += window_size;
+                            let out = window.close(
+                                time,
+                                window_size,
+                                allowed_lateness,
+                                &on_new_input,
+                                &on_notify,
+                            );
+                            output.session(&cap.delayed(&time)).give(Ok(out));
+                        }
+                    }
+                    (None, _) => {}
+                }
+
+                window.prune(&notificator.frontier(0), allowed_lateness);
             });
 
-            let borrow = ref2.borrow();
-            if let Some(cap) = &*borrow {
-                // setup next notification to the previous notification time + window_size
-                next_notification_time = *cap.time() + window_size;
-                notificator.notify_at(cap.delayed(&next_notification_time));
-            }
-        })
+        // split the outputs of the windows from the amendments
+        let (results, amendments) = output.branch(|_, out| out.is_err());
+        (results.flat_map(|out| out.ok()), amendments.flat_map(|out| out.err()))
     }
 }
@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Shape of the windows, all sizes are in seconds of event time.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum WindowKind {
+    /// consecutive, non-overlapping windows of `size`
+    Tumbling { size: u64 },
+    /// windows of `size`, a new one every `slide` (`size` must be a multiple of `slide`)
+    Sliding { size: u64, slide: u64 },
+    /// windows of activity of a key, closed after `gap` without events of that key
+    Session { gap: u64 },
+}
+
+/// Aggregation of the events of a key in a window.
+///
+/// `merge` combines the aggregates of two disjoint sets of events,
+/// it is used to combine the panes of a sliding window and to merge sessions.
+pub trait Aggregator: Default + Clone {
+    type Input;
+    type Output;
+    fn add(&mut self, input: &Self::Input);
+    fn merge(&mut self, other: &Self);
+    fn result(&self) -> Self::Output;
+}
+
+/// sum of the values of the events
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Sum(pub u64);
+
+impl Aggregator for Sum {
+    type Input = u64;
+    type Output = u64;
+    fn add(&mut self, value: &u64) { self.0 += value; }
+    fn merge(&mut self, other: &Sum) { self.0 += other.0; }
+    fn result(&self) -> u64 { self.0 }
+}
+
+/// maximum of the values of the events (e.g. the latest timestamp)
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+pub struct Max(pub u64);
+
+impl Aggregator for Max {
+    type Input = u64;
+    type Output = u64;
+    fn add(&mut self, value: &u64) { self.0 = self.0.max(*value); }
+    fn merge(&mut self, other: &Max) { self.0 = self.0.max(other.0); }
+    fn result(&self) -> u64 { self.0 }
+}
+
+/// A session window of a key: its first and last event, and their aggregate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session<A> {
+    pub start:     u64,
+    pub end:       u64,
+    pub aggregate: A,
+}
+
+/// Per-key window aggregates, driven by the event timestamps.
+///
+/// Tumbling and sliding windows are split into panes of `slide` seconds
+/// (`size` for tumbling windows): every event is aggregated into exactly one
+/// pane, and the aggregate of a window is the merge of the panes it covers.
+/// A pane covers the events in `(end - slide, end]`, pane ends are aligned
+/// to the first time passed to `align` (e.g. the first notification time
+/// of `window_notify`), or to multiples of `slide` if it is never called.
+///
+/// Session windows are kept per key, and merged when an event bridges two of them.
+///
+/// Expired panes and closed sessions are dropped by `expire`,
+/// so that the operators using windows do not need to track the age of their state.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Windows<K: Eq + Hash, A> {
+    kind:     WindowKind,
+    // end of a pane, all the panes are aligned to it
+    origin:   Option<u64>,
+    // end of the pane --> key --> aggregate of the events of the key in the pane
+    panes:    BTreeMap<u64, HashMap<K, A>>,
+    // key --> open sessions, sorted by start
+    sessions: HashMap<K, Vec<Session<A>>>,
+}
+
+impl<K: Eq + Hash + Clone, A: Aggregator> Windows<K, A> {
+    pub fn new(kind: WindowKind) -> Windows<K, A> {
+        if let WindowKind::Sliding { size, slide } = kind {
+            assert!(slide > 0 && size % slide == 0, "window size must be a multiple of the slide");
+        }
+        Windows {
+            kind:     kind,
+            origin:   None,
+            panes:    BTreeMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// align the end of the panes to `time`, only the first call has an effect
+    pub fn align(&mut self, time: u64) {
+        if self.origin.is_none() {
+            self.origin = Some(time);
+        }
+    }
+
+    /// aggregate an event of `key` at `time`
+    pub fn insert(&mut self, key: K, time: u64, input: &A::Input) {
+        match self.kind {
+            WindowKind::Session { gap } => self.insert_session(key, time, input, gap),
+            _ => {
+                let end = self.pane_end(time);
+                let pane = self.panes.entry(end).or_insert_with(HashMap::new);
+                pane.entry(key).or_insert_with(A::default).add(input);
+            }
+        }
+    }
+
+    /// aggregates of the window ending at `end` (tumbling and sliding windows),
+    /// or of the sessions open at `end` (session windows), i.e. started by `end`
+    /// and not closed yet: their last event is less than `gap` before `end`
+    pub fn aggregate(&self, end: u64) -> HashMap<K, A> {
+        match self.kind {
+            WindowKind::Session { gap } => {
+                let mut result = HashMap::<K, A>::new();
+                for (key, sessions) in self.sessions.iter() {
+                    for session in sessions.iter().filter(|s| s.start <= end && end < s.end + gap) {
+                        result
+                            .entry(key.clone())
+                            .or_insert_with(A::default)
+                            .merge(&session.aggregate);
+                    }
+                }
+                result
+            }
+            _ => self.aggregate_over(end, self.size()),
+        }
+    }
+
+    /// aggregates of the last `length` seconds of the window ending at `end`
+    /// (tumbling and sliding windows), `length` should be a multiple of the slide
+    pub fn aggregate_over(&self, end: u64, length: u64) -> HashMap<K, A> {
+        let mut result = HashMap::<K, A>::new();
+        let start = end.saturating_sub(length.min(self.size()));
+        for (_, pane) in self.panes.range(start + 1..=end) {
+            for (key, aggregate) in pane.iter() {
+                result.entry(key.clone()).or_insert_with(A::default).merge(aggregate);
+            }
+        }
+        result
+    }
+
+    /// results of the window ending at `end`, see `aggregate`
+    pub fn results(&self, end: u64) -> HashMap<K, A::Output> {
+        self.aggregate(end).into_iter().map(|(key, aggregate)| (key, aggregate.result())).collect()
+    }
+
+    /// drop the panes that no window ending after `time` covers,
+    /// and return the sessions closed by `time`
+    pub fn expire(&mut self, time: u64) -> Vec<(K, Session<A>)> {
+        match self.kind {
+            WindowKind::Session { gap } => {
+                let mut closed = Vec::new();
+                for (key, sessions) in self.sessions.iter_mut() {
+                    let (done, open) =
+                        sessions.drain(..).partition::<Vec<_>, _>(|s| s.end + gap <= time);
+                    *sessions = open;
+                    closed.extend(done.into_iter().map(|session| (key.clone(), session)));
+                }
+                self.sessions.retain(|_, sessions| !sessions.is_empty());
+                closed.sort_by_key(|(_, session)| session.start);
+                closed
+            }
+            _ => {
+                let start = time.saturating_sub(self.size());
+                self.panes = self.panes.split_off(&(start + 1));
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.panes.is_empty() && self.sessions.is_empty() }
+
+    fn size(&self) -> u64 {
+        match self.kind {
+            WindowKind::Tumbling { size } => size,
+            WindowKind::Sliding { size, .. } => size,
+            WindowKind::Session { gap } => gap,
+        }
+    }
+
+    fn slide(&self) -> u64 {
+        match self.kind {
+            WindowKind::Sliding { slide, .. } => slide,
+            _ => self.size(),
+        }
+    }
+
+    /// end of the pane containing `time`, i.e. the first aligned pane end >= `time`
+    fn pane_end(&self, time: u64) -> u64 {
+        let slide = self.slide();
+        let offset = self.origin.unwrap_or(0) % slide;
+        if time <= offset {
+            offset
+        } else {
+            offset + (time - offset + slide - 1) / slide * slide
+        }
+    }
+
+    /// add the event to the sessions of the key, merging the ones it bridges
+    fn insert_session(&mut self, key: K, time: u64, input: &A::Input, gap: u64) {
+        let sessions = self.sessions.entry(key).or_insert_with(Vec::new);
+
+        let mut session = Session { start: time, end: time, aggregate: A::default() };
+        session.aggregate.add(input);
+
+        // sessions within `gap` of the event are merged into a single one
+        let (close, mut others) = sessions
+            .drain(..)
+            .partition::<Vec<_>, _>(|s| s.start <= time + gap && time <= s.end + gap);
+        for other in close {
+            session.start = session.start.min(other.start);
+            session.end = session.end.max(other.end);
+            session.aggregate.merge(&other.aggregate);
+        }
+
+        others.push(session);
+        others.sort_by_key(|s| s.start);
+        *sessions = others;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pane_end_is_aligned_to_the_origin() {
+        let mut windows = Windows::<u64, Sum>::new(WindowKind::Sliding { size: 60, slide: 20 });
+        windows.align(130);
+        windows.align(135); // only the first call has an effect
+
+        // times up to the offset of the origin fall in the first pane
+        assert_eq!(windows.pane_end(5), 10);
+        assert_eq!(windows.pane_end(10), 10);
+        assert_eq!(windows.pane_end(11), 30);
+        assert_eq!(windows.pane_end(30), 30);
+        assert_eq!(windows.pane_end(31), 50);
+    }
+
+    #[test]
+    fn aggregate_over_covers_the_panes_in_range() {
+        let mut windows = Windows::<u64, Sum>::new(WindowKind::Sliding { size: 60, slide: 20 });
+        for &time in [0, 1, 20, 21, 60, 61].iter() {
+            windows.insert(1, time, &1);
+        }
+
+        // (0, 60]: the pane ending at 0 is excluded, the one ending at 60 included
+        assert_eq!(windows.aggregate_over(60, 60)[&1].result(), 4);
+        assert_eq!(windows.aggregate_over(60, 20)[&1].result(), 1);
+        // the length is capped to the size of the window
+        assert_eq!(windows.aggregate_over(60, 120)[&1].result(), 4);
+        assert_eq!(windows.results(80)[&1], 3);
+        assert!(windows.aggregate_over(120, 20).is_empty());
+    }
+
+    #[test]
+    fn expire_drops_the_panes_out_of_every_window() {
+        let mut windows = Windows::<u64, Sum>::new(WindowKind::Sliding { size: 60, slide: 20 });
+        for &time in [0, 1, 20, 21, 60, 61].iter() {
+            windows.insert(1, time, &1);
+        }
+
+        assert!(windows.expire(80).is_empty());
+        assert_eq!(windows.panes.keys().cloned().collect::<Vec<_>>(), vec![40, 60, 80]);
+        assert_eq!(windows.results(80)[&1], 3);
+        assert_eq!(windows.aggregate_over(60, 60)[&1].result(), 2);
+
+        windows.expire(140);
+        assert!(windows.is_empty());
+    }
+
+    #[test]
+    fn tumbling_windows_do_not_overlap() {
+        let mut windows = Windows::<u64, Max>::new(WindowKind::Tumbling { size: 30 });
+        for &time in [10, 30, 31].iter() {
+            windows.insert(1, time, &time);
+        }
+
+        assert_eq!(windows.results(30)[&1], 30);
+        assert_eq!(windows.results(60)[&1], 31);
+    }
+
+    #[test]
+    fn sessions_are_merged_by_a_bridging_event() {
+        let mut windows = Windows::<u64, Sum>::new(WindowKind::Session { gap: 10 });
+        windows.insert(1, 100, &1);
+        windows.insert(1, 118, &1);
+        assert_eq!(windows.sessions[&1].len(), 2);
+
+        // within the gap of both sessions
+        windows.insert(1, 109, &1);
+        assert_eq!(windows.sessions[&1].len(), 1);
+        assert_eq!((windows.sessions[&1][0].start, windows.sessions[&1][0].end), (100, 118));
+        assert_eq!(windows.results(100)[&1], 3);
+        assert!(windows.results(99).is_empty());
+
+        // the session is closed `gap` after its last event
+        assert!(windows.expire(127).is_empty());
+        let closed = windows.expire(128);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, 1);
+        assert_eq!(closed[0].1.aggregate.result(), 3);
+        assert!(windows.is_empty());
+    }
+
+    #[test]
+    fn only_the_open_sessions_are_aggregated() {
+        let mut windows = Windows::<u64, Sum>::new(WindowKind::Session { gap: 10 });
+        windows.insert(1, 100, &1);
+        windows.insert(1, 200, &2);
+        windows.insert(2, 105, &4);
+
+        // the sessions of key 1 ending at 100 and of key 2 are still open at 109 and 114
+        assert_eq!(windows.results(109)[&1], 1);
+        assert_eq!(windows.results(114)[&2], 4);
+        // closed `gap` after their last event, even if they are not expired yet
+        assert!(!windows.results(110).contains_key(&1));
+        assert!(windows.results(150).is_empty());
+        assert_eq!(windows.results(200)[&1], 2);
+        assert!(!windows.results(200).contains_key(&2));
+    }
+}
@@ -39,7 +39,7 @@ use math::round;
 /// _upper_bound_. This is to disallow marking user activity as spam if there is
 /// very little or no spam activity across all users.
 ///
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Percentile {
     perc:          u64,
     buckets:       Vec<u64>,
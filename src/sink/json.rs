@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use timely::dataflow::operators::Inspect;
+use timely::dataflow::{Scope, Stream};
+use timely::Data;
+
+lazy_static! {
+    // path --> file, shared by all the sinks (of every worker) writing to it
+    static ref FILES: Mutex<HashMap<PathBuf, Arc<Mutex<File>>>> = Mutex::new(HashMap::new());
+}
+
+/// the file at `path`, opened in append mode by its first sink
+fn shared_file(path: &PathBuf) -> Arc<Mutex<File>> {
+    let mut files = FILES.lock().unwrap();
+    let file = files.entry(path.clone()).or_insert_with(|| {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect(&format!("could not open {:?}", path));
+        Arc::new(Mutex::new(file))
+    });
+    file.clone()
+}
+
+/// A single line of the output, one for each record emitted by a query
+#[derive(Serialize)]
+pub struct JsonRecord<'a, D: Serialize> {
+    pub query:     usize,
+    pub worker:    usize,
+    pub timestamp: u64,
+    pub data:      &'a D,
+}
+
+/// Write every record of the stream as a JSON line, together
+/// with the query id, the worker id and the timestamp of the window.
+///
+/// If `path` is None the lines are written to stdout, otherwise they are
+/// appended to the file, opened once and shared by all the sinks writing to it
+/// (the lines of a batch are written at once).
+pub trait JsonSink<G: Scope, D: Data + Serialize> {
+    fn json_sink(&self, query: usize, worker_id: usize, path: &Option<PathBuf>);
+}
+
+impl<G: Scope<Timestamp = u64>, D: Data + Serialize> JsonSink<G, D> for Stream<G, D> {
+    fn json_sink(&self, query: usize, worker_id: usize, path: &Option<PathBuf>) {
+        let file = path.as_ref().map(shared_file);
+
+        self.inspect_batch(move |&timestamp, data| {
+            let mut lines = String::new();
+            for d in data {
+                let record =
+                    JsonRecord { query: query, worker: worker_id, timestamp: timestamp, data: d };
+                let line = serde_json::to_string(&record).expect("failed to serialize record");
+                lines.push_str(&format!("{}\n", line));
+            }
+            match &file {
+                Some(file) => file.lock().unwrap().write_all(lines.as_bytes()),
+                None => io::stdout().write_all(lines.as_bytes()),
+            }
+            .expect("failed to write records");
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::event::{DeadLetter, LateEvent};
+use crate::operators::post_trees::DuplicateEvent;
+use crate::operators::trending::TrendingPost;
+use crate::operators::window_notify::Amendment;
+
+pub mod json;
+
+/// Where the output of a query is written to.
+///
+/// - `inspect`:      colored text on stdout (default)
+/// - `json`:         JSON lines on stdout
+/// - `json:<path>`:  JSON lines appended to the given file
+/// - `kafka:<topic>`: JSON messages published on the given kafka topic
+#[derive(Clone, Debug)]
+pub enum Sink {
+    Inspect,
+    Json(Option<PathBuf>),
+    Kafka(String),
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Sink, String> {
+        match s {
+            "inspect" => Ok(Sink::Inspect),
+            "json" | "json:-" => Ok(Sink::Json(None)),
+            _ if s.starts_with("json:") => Ok(Sink::Json(Some(PathBuf::from(&s["json:".len()..])))),
+            _ if s.starts_with("kafka:") => Ok(Sink::Kafka(s["kafka:".len()..].to_string())),
+            _ => Err(format!(
+                "unknown sink {:?}, expected inspect, json, json:<path> or kafka:<topic>",
+                s
+            )),
+        }
+    }
+}
+
+/// Outputs that can be split into values keyed by a post or person id,
+/// used to key the messages published on kafka (None if there is no meaningful key).
+pub trait Keyed {
+    type Value: Serialize;
+    fn keyed(&self) -> Vec<(Option<u64>, &Self::Value)>;
+}
+
+// e.g. post id --> stats, person id --> recommendations
+impl<V: Serialize> Keyed for HashMap<u64, V> {
+    type Value = V;
+    fn keyed(&self) -> Vec<(Option<u64>, &V)> { self.iter().map(|(&k, v)| (Some(k), v)).collect() }
+}
+
+// e.g. person id of a spammer
+impl Keyed for u64 {
+    type Value = u64;
+    fn keyed(&self) -> Vec<(Option<u64>, &u64)> { vec![(Some(*self), self)] }
+}
+
+impl Keyed for DeadLetter {
+    type Value = DeadLetter;
+    fn keyed(&self) -> Vec<(Option<u64>, &DeadLetter)> { vec![(None, self)] }
+}
+
+// keyed by the person that created the event
+impl Keyed for LateEvent {
+    type Value = LateEvent;
+    fn keyed(&self) -> Vec<(Option<u64>, &LateEvent)> { vec![(Some(self.event.person_id()), self)] }
+}
+
+// keyed by the person that created the duplicate
+impl Keyed for DuplicateEvent {
+    type Value = DuplicateEvent;
+    fn keyed(&self) -> Vec<(Option<u64>, &DuplicateEvent)> {
+        vec![(Some(self.event.person_id()), self)]
+    }
+}
+
+// ranking of the trending posts, keyed by post id
+impl Keyed for Vec<TrendingPost> {
+    type Value = TrendingPost;
+    fn keyed(&self) -> Vec<(Option<u64>, &TrendingPost)> {
+        self.iter().map(|post| (Some(post.post_id), post)).collect()
+    }
+}
+
+// an amendment replaces the whole output of a window
+impl<O: Serialize> Keyed for Amendment<O> {
+    type Value = Amendment<O>;
+    fn keyed(&self) -> Vec<(Option<u64>, &Amendment<O>)> { vec![(None, self)] }
+}
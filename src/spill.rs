@@ -0,0 +1,147 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+const NUM_BUCKETS: u64 = 1024;
+// buckets are compacted once they hold this many entries more than twice their keys
+const MIN_GARBAGE: usize = 64;
+
+/// Append-only key-value store on disk, for state evicted from memory.
+///
+/// Entries are hashed by key into NUM_BUCKETS files `<dir>/bucket-<n>.bin`,
+/// each a sequence of bincode `(key, value)` records. Nothing is kept in memory
+/// per key: a lookup scans the bucket of the key, and the latest entry wins.
+///
+/// Writing a key again supersedes its previous entry: once most entries of a bucket
+/// are superseded, the bucket is rewritten with the latest entry of each key only.
+/// Entries are never deleted, so that the store stays consistent with the
+/// operator state restored from an older checkpoint.
+pub struct SpillStore<K, V> {
+    dir:     PathBuf,
+    // bucket --> number of entries in the file, including the superseded ones
+    entries: Vec<usize>,
+    // bucket --> number of keys, as of the latest compaction
+    keys:    Vec<usize>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V> SpillStore<K, V>
+where
+    K: Serialize + DeserializeOwned + Hash + Eq,
+    V: Serialize + DeserializeOwned,
+{
+    /// empty store, the entries left in `dir` by a previous run are deleted
+    pub fn new(dir: PathBuf) -> SpillStore<K, V> {
+        if dir.exists() {
+            fs::remove_dir_all(&dir).expect("failed to clear spill dir");
+        }
+        SpillStore::open(dir)
+    }
+
+    /// store with the entries left in `dir`, e.g. by the run restored from a checkpoint
+    pub fn open(dir: PathBuf) -> SpillStore<K, V> {
+        fs::create_dir_all(&dir).expect("failed to create spill dir");
+        let mut store = SpillStore {
+            dir:     dir,
+            entries: vec![0; NUM_BUCKETS as usize],
+            keys:    vec![0; NUM_BUCKETS as usize],
+            phantom: PhantomData,
+        };
+        for bucket in 0..NUM_BUCKETS {
+            let entries = store.scan(bucket);
+            store.entries[bucket as usize] = entries.len();
+            store.keys[bucket as usize] = Self::latest(entries).len();
+        }
+        store
+    }
+
+    fn bucket(key: &K) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() % NUM_BUCKETS
+    }
+
+    fn path(&self, bucket: u64) -> PathBuf { self.dir.join(format!("bucket-{}.bin", bucket)) }
+
+    /// entries of the bucket, in the order they were written
+    fn scan(&self, bucket: u64) -> Vec<(K, V)> {
+        let file = match File::open(self.path(bucket)) {
+            Ok(file) => file,
+            Err(_) => return Vec::new(),
+        };
+        let mut reader = BufReader::new(file);
+        let mut entries = Vec::new();
+        while let Ok(entry) = bincode::deserialize_from::<_, (K, V)>(&mut reader) {
+            entries.push(entry);
+        }
+        entries
+    }
+
+    /// latest entry of each key
+    fn latest(entries: Vec<(K, V)>) -> HashMap<K, V> { entries.into_iter().collect() }
+
+    /// append the entries to their buckets
+    pub fn write(&mut self, entries: Vec<(K, V)>) {
+        let mut buckets = HashMap::<u64, Vec<(K, V)>>::new();
+        for (key, value) in entries {
+            buckets.entry(Self::bucket(&key)).or_insert(Vec::new()).push((key, value));
+        }
+
+        for (bucket, entries) in buckets {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(bucket))
+                .expect("failed to open spill bucket");
+            let mut writer = BufWriter::new(file);
+            for entry in entries.iter() {
+                bincode::serialize_into(&mut writer, entry).expect("failed to write spill bucket");
+            }
+            writer.flush().expect("failed to write spill bucket");
+
+            // the keys written since the latest compaction may be new, or supersede others
+            self.entries[bucket as usize] += entries.len();
+            if self.entries[bucket as usize] > 2 * self.keys[bucket as usize] + MIN_GARBAGE {
+                self.compact(bucket);
+            }
+        }
+    }
+
+    /// rewrite the bucket with the latest entry of each key only
+    fn compact(&mut self, bucket: u64) {
+        let path = self.path(bucket);
+        let tmp_path = self.dir.join(format!("bucket-{}.tmp", bucket));
+        let mut writer =
+            BufWriter::new(File::create(&tmp_path).expect("failed to create spill bucket"));
+
+        let latest = Self::latest(self.scan(bucket));
+        for entry in latest.iter() {
+            bincode::serialize_into(&mut writer, &entry).expect("failed to write spill bucket");
+        }
+        writer.flush().expect("failed to write spill bucket");
+
+        fs::rename(&tmp_path, &path).expect("failed to replace spill bucket");
+        self.entries[bucket as usize] = latest.len();
+        self.keys[bucket as usize] = latest.len();
+    }
+
+    /// latest value written for the key, if any
+    pub fn lookup(&self, key: &K) -> Option<V> {
+        let file = File::open(self.path(Self::bucket(key))).ok()?;
+        let mut reader = BufReader::new(file);
+        let mut latest = None;
+        while let Ok((entry_key, value)) = bincode::deserialize_from::<_, (K, V)>(&mut reader) {
+            if entry_key == *key {
+                latest = Some(value);
+            }
+        }
+        latest
+    }
+}
@@ -0,0 +1,74 @@
+use crate::event::{self, DeserializeError, Event, EventKind};
+
+pub use dspa_common::wire::{Envelope, MessageKind, SCHEMA_VERSION};
+
+/// A record flowing out of the source operators, waiting to be deserialized.
+///
+/// `version` is 0 for bare pipe-separated records (the legacy format),
+/// whose kind is then inferred by the deserializer.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub version: u32,
+    pub kind:    Option<EventKind>,
+    pub payload: String,
+}
+
+impl abomonation::Abomonation for Record {}
+
+impl Record {
+    pub fn csv(kind: Option<EventKind>, payload: String) -> Record {
+        Record { version: 0, kind: kind, payload: payload }
+    }
+
+    pub fn deserialize(&self) -> Result<Event, DeserializeError> {
+        if self.version > SCHEMA_VERSION {
+            return Err(DeserializeError::UnsupportedVersion { version: self.version });
+        }
+        match self.kind {
+            Some(kind) => event::deserialize_as(kind, self.payload.clone()),
+            None => event::deserialize(self.payload.clone()),
+        }
+    }
+}
+
+/// A decoded kafka message, with the event timestamp if it could be read
+pub enum Message {
+    Event(Record, Option<u64>),
+    Watermark(u64),
+    // the producer sent the whole stream of the partition
+    End,
+}
+
+/// decode a kafka message, accepting both the JSON envelope and
+/// the legacy format (bare records and `WATERMARK|<timestamp>` lines).
+pub fn decode(text: &str) -> Message {
+    if let Ok(envelope) = serde_json::from_str::<Envelope>(text) {
+        let kind = match envelope.kind {
+            MessageKind::Watermark => return Message::Watermark(envelope.timestamp),
+            MessageKind::End => return Message::End,
+            MessageKind::Post => EventKind::Post,
+            MessageKind::Comment => EventKind::Comment,
+            MessageKind::Like => EventKind::Like,
+        };
+        let record =
+            Record { version: envelope.version, kind: Some(kind), payload: envelope.payload };
+        return Message::Event(record, Some(envelope.timestamp));
+    }
+
+    if text.starts_with("WATERMARK") {
+        // format is WATERMARK|<timestamp>
+        if let Some(t) = text.split("|").nth(1).and_then(|t| t.trim().parse::<u64>().ok()) {
+            return Message::Watermark(t);
+        }
+    }
+
+    // creation date is always the 3rd field, malformed records are forwarded anyway:
+    // they are routed to the dead-letter stream after deserialization
+    let timestamp = text
+        .split("|")
+        .nth(2)
+        .and_then(|date_str| event::parse_date(date_str.trim()))
+        .map(|date| date.timestamp() as u64);
+
+    Message::Event(Record::csv(None, text.to_string()), timestamp)
+}
@@ -0,0 +1,269 @@
+//! Golden-output regression tests.
+//!
+//! Every directory under `dataset/tests/task*/` is replayed through the whole
+//! pipeline (`post_trees` -> `active_posts` / `friend_recommendations` / spam detection)
+//! with the file source, and the outputs emitted at each window are compared with
+//! the expected files `expected_q<ID>.txt` checked in next to the streams.
+//! The lines of query 1 also carry the likes and the stats of every horizon,
+//! that the `Display` of the stats leaves out.
+//!
+//! The streams are also replayed by two workers: the windows of each worker start
+//! from its own first event, so only the latest all-time stats of every post are compared
+//! with the ones of the single worker.
+//!
+//! Run with `DSPA_BLESS=1 cargo test --test golden` to regenerate the expected files,
+//! a missing expected file is a failure otherwise.
+//! Query 2 needs the static data in postgres, it is skipped if the database is not reachable
+//! or if its expected file has not been blessed yet.
+
+extern crate dspa;
+extern crate postgres;
+extern crate timely;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use timely::dataflow::operators::{Concat, Exchange, Inspect, Map};
+
+use dspa::checkpoint::Checkpointer;
+use dspa::file;
+use dspa::operators::active_posts::{ActivePosts, PostStatus, Stats};
+use dspa::operators::friend_recommendations::{FriendRecommendations, POSTGRES_URI};
+use dspa::operators::post_freq::PostFrequency;
+use dspa::operators::post_trees::{route_replies, PostTrees};
+use dspa::operators::unique_words::UniqueWords;
+use dspa::wire::Record;
+
+const TESTS_DIR: &str = "dataset/tests";
+const RECOMMENDATION_PIDS: [u64; 3] = [800, 801, 802];
+
+/// output lines of each query, (window timestamp, line)
+type Lines = Arc<Mutex<Vec<(u64, String)>>>;
+
+/// post ID --> (window timestamp, all-time stats) of the latest window the post was active in
+type Totals = Arc<Mutex<HashMap<u64, (u64, String)>>>;
+
+struct Outputs {
+    q1:     Lines,
+    q2:     Lines,
+    q3:     Lines,
+    totals: Totals,
+}
+
+/// all-time stats of a post, including its likes
+fn render_totals(stats: &Stats) -> String {
+    let mut likers = stats.likers.iter().collect::<Vec<_>>();
+    likers.sort();
+    format!("{}, likes = {}, likers = {:?}", stats, stats.num_likes, likers)
+}
+
+/// line of a post in the output of query 1: all-time stats, then the stats of every horizon
+fn render_post(post_id: u64, status: &PostStatus) -> String {
+    let stats = match status {
+        PostStatus::Active(stats) => stats,
+        PostStatus::Inactive => return format!("post {} -- inactive", post_id),
+    };
+    let windows = stats
+        .windows
+        .iter()
+        .map(|(horizon, w)| {
+            format!(
+                "{}h: comments = {}, replies = {}, people = {}, likes = {}, likers = {}, \
+                 likes/h = {:.3}",
+                horizon / 3600,
+                w.num_comments,
+                w.num_replies,
+                w.num_people,
+                w.num_likes,
+                w.num_likers,
+                w.like_velocity
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("post {} -- {} -- {}", post_id, render_totals(stats), windows.join("; "))
+}
+
+/// replay the streams in `dir` and capture the output of every query
+fn run_pipeline(dir: &Path, config: timely::Configuration, with_recommendations: bool) -> Outputs {
+    let outputs = Outputs {
+        q1:     Arc::new(Mutex::new(Vec::new())),
+        q2:     Arc::new(Mutex::new(Vec::new())),
+        q3:     Arc::new(Mutex::new(Vec::new())),
+        totals: Arc::new(Mutex::new(HashMap::new())),
+    };
+
+    let (q1, q2, q3) = (outputs.q1.clone(), outputs.q2.clone(), outputs.q3.clone());
+    let totals = outputs.totals.clone();
+    let dir = dir.to_path_buf();
+
+    timely::execute(config, move |worker| {
+        let widx = worker.index();
+        let num_workers = worker.peers();
+        let (q1, q2, q3) = (q1.clone(), q2.clone(), q3.clone());
+        let totals = totals.clone();
+        let checkpointer = Checkpointer::disabled();
+
+        worker.dataflow::<u64, _, _>(|scope| {
+            let event_stream =
+                file::source::record_stream(scope, dir.clone(), widx, num_workers, &checkpointer)
+                    .map(|record: Record| record.deserialize().expect("malformed test record"));
+
+            let (stat_updates, rec_updates, _) =
+                route_replies(&event_stream, widx, &checkpointer).post_trees(widx, &checkpointer);
+
+            stat_updates.active_posts(widx, &checkpointer).0.inspect_batch(move |t, batch| {
+                let mut lines = q1.lock().unwrap();
+                let mut totals = totals.lock().unwrap();
+                for stats in batch {
+                    for (&post_id, status) in stats.iter() {
+                        lines.push((*t, render_post(post_id, status)));
+                        if let PostStatus::Active(stats) = status {
+                            let latest = totals.entry(post_id).or_insert((*t, String::new()));
+                            if latest.0 <= *t {
+                                *latest = (*t, render_totals(stats));
+                            }
+                        }
+                    }
+                }
+            });
+
+            if with_recommendations {
+                rec_updates
+                    .friend_recommendations(&RECOMMENDATION_PIDS.to_vec(), &checkpointer)
+                    .0
+                    .inspect_batch(move |t, batch| {
+                        let mut lines = q2.lock().unwrap();
+                        for recs in batch {
+                            let mut pids = recs.keys().collect::<Vec<_>>();
+                            pids.sort();
+                            for pid in pids {
+                                lines.push((*t, format!("person {} -- {:?}", pid, recs[pid])));
+                            }
+                        }
+                    });
+            }
+
+            let events_by_pid = event_stream.exchange(|event| event.person_id());
+            let spam1 = events_by_pid.post_frequency(widx, &checkpointer);
+            let spam2 = events_by_pid.unique_words(widx, &checkpointer);
+            spam1.concat(&spam2).inspect_batch(move |t, batch| {
+                let mut lines = q3.lock().unwrap();
+                for spam_pid in batch {
+                    lines.push((*t, format!("spam {}", spam_pid)));
+                }
+            });
+        });
+    })
+    .expect("Timely computation failed somehow");
+
+    outputs
+}
+
+/// render the captured lines, sorted by window timestamp
+fn render(lines: &Lines) -> String {
+    let mut lines = lines.lock().unwrap().clone();
+    lines.sort();
+    lines.iter().map(|(t, line)| format!("{} {}\n", t, line)).collect()
+}
+
+/// compare the output against the expected file, returns an error message on mismatch
+fn check(expected_path: &PathBuf, actual: &str, bless: bool) -> Result<(), String> {
+    if bless {
+        fs::write(expected_path, actual).unwrap();
+        println!("[golden] recorded {:?}", expected_path);
+        return Ok(());
+    }
+
+    let expected = match fs::read_to_string(expected_path) {
+        Ok(expected) => expected,
+        Err(_) => return Err(format!("missing expected file {:?}", expected_path)),
+    };
+    if expected == actual {
+        return Ok(());
+    }
+
+    let diff = expected
+        .lines()
+        .map(Some)
+        .chain(std::iter::repeat(None))
+        .zip(actual.lines().map(Some).chain(std::iter::repeat(None)))
+        .take_while(|pair| *pair != (None, None))
+        .filter(|(e, a)| e != a)
+        .map(|(e, a)| format!("  - {}\n  + {}", e.unwrap_or(""), a.unwrap_or("")))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Err(format!("{:?} does not match:\n{}", expected_path, diff))
+}
+
+fn test_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for task in fs::read_dir(TESTS_DIR).unwrap() {
+        for test in fs::read_dir(task.unwrap().path()).unwrap() {
+            let path = test.unwrap().path();
+            if path.join("posts_event_stream.csv").exists() {
+                dirs.push(path);
+            }
+        }
+    }
+    dirs.sort();
+    dirs
+}
+
+#[test]
+fn golden_outputs() {
+    let bless = std::env::var("DSPA_BLESS").is_ok();
+    let with_recommendations = postgres::Client::connect(POSTGRES_URI, postgres::NoTls).is_ok();
+    if !with_recommendations {
+        println!("[golden] postgres is not reachable, skipping query 2");
+    }
+
+    let mut failures = Vec::new();
+    for dir in test_dirs() {
+        println!("[golden] running {:?}", dir);
+        let outputs = run_pipeline(&dir, timely::Configuration::Thread, with_recommendations);
+
+        let mut queries = vec![(1, &outputs.q1), (3, &outputs.q3)];
+        if with_recommendations {
+            queries.push((2, &outputs.q2));
+        }
+
+        for (query, lines) in queries {
+            let expected_path = dir.join(format!("expected_q{}.txt", query));
+            // the expected outputs of query 2 can only be blessed where postgres is set up
+            if query == 2 && !bless && !expected_path.exists() {
+                println!("[golden] no expected output of query 2 in {:?}, skipping it", dir);
+                continue;
+            }
+            if let Err(msg) = check(&expected_path, &render(lines), bless) {
+                failures.push(msg);
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "golden outputs differ:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn multi_worker_totals() {
+    let mut failures = Vec::new();
+    for dir in test_dirs() {
+        println!("[golden] running {:?} with 2 workers", dir);
+        let single = run_pipeline(&dir, timely::Configuration::Thread, false);
+        let multi = run_pipeline(&dir, timely::Configuration::Process(2), false);
+
+        // post ID --> latest all-time stats, the windows differ between the runs
+        let totals = |outputs: &Outputs| {
+            let totals = outputs.totals.lock().unwrap();
+            totals.iter().map(|(&id, (_, stats))| (id, stats.clone())).collect::<BTreeMap<_, _>>()
+        };
+        let (expected, actual) = (totals(&single), totals(&multi));
+        if expected != actual {
+            failures.push(format!("{:?}:\n  - {:?}\n  + {:?}", dir, expected, actual));
+        }
+    }
+
+    assert!(failures.is_empty(), "2 workers disagree with 1 worker:\n{}", failures.join("\n"));
+}